@@ -32,6 +32,8 @@
           git-append = craneLib.buildPackage (commonArgs // {
             pname = "git-append";
             inherit cargoArtifacts;
+            # the signing tests generate and sign with SSH keys
+            nativeCheckInputs = with pkgs; [ openssh ];
           });
 
         in
@@ -43,6 +45,7 @@
               cargo
               pkg-config
               openssl
+              openssh
             ];
           };
 
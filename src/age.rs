@@ -1,26 +1,31 @@
-use std::io::{BufRead, Write};
+use std::io::Write;
 
 use age::secrecy::SecretString;
 
-pub fn encrypt(plaintext: &Vec<u8>, passphrase: Box<str>) -> Vec<u8> {
+use crate::file::{split_lines, LineEnding};
+
+pub fn encrypt(plaintext: &[u8], passphrase: Box<str>) -> Result<Vec<u8>, String> {
     let encryptor = age::Encryptor::with_user_passphrase(SecretString::new(passphrase));
     let mut encrypted = vec![];
-    let mut writer = encryptor.wrap_output(&mut encrypted).unwrap();
-    writer.write_all(&plaintext).unwrap();
-    writer.finish().unwrap();
-    encrypted
+    let mut writer = encryptor
+        .wrap_output(&mut encrypted)
+        .map_err(|e| format!("Could not encrypt: {}", e))?;
+    writer
+        .write_all(plaintext)
+        .and_then(|_| writer.finish())
+        .map_err(|e| format!("Could not encrypt: {}", e))?;
+    Ok(encrypted)
 }
 
-pub fn decrypt(encrypted: Vec<u8>, passphrase: Box<str>) -> Vec<Vec<u8>> {
-    let reader = age::decrypt(
+pub fn decrypt(
+    encrypted: Vec<u8>,
+    passphrase: Box<str>,
+) -> Result<(Vec<Vec<u8>>, LineEnding), String> {
+    let decrypted = age::decrypt(
         &age::scrypt::Identity::new(SecretString::new(passphrase)),
         &encrypted,
     )
-    .unwrap();
+    .map_err(|e| format!("Could not decrypt: {}", e))?;
 
-    let decrypted = reader.lines();
-    decrypted
-        .into_iter()
-        .map(|l| l.unwrap().as_bytes().into())
-        .collect()
+    Ok(split_lines(&decrypted))
 }
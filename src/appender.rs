@@ -1,42 +1,73 @@
 use std::collections::{BTreeSet, HashSet};
-use std::str;
 
 use git2::Repository;
-use regex::Regex;
+use regex::bytes::Regex;
 
 use crate::config::Feature;
+use crate::file::{split_lines, LineEnding};
+use crate::log;
 use crate::{age::decrypt, config::GitLink, file::get_file_contents, git::get_blob_from_head};
 
+/// The reference a link is read from: the local `master`, which also has the commits queued while offline, or the
+/// remote-tracking branch of another `source_branch` (or of a repository without any commit yet).
+pub fn link_reference(repo: &Repository, file_appender: &GitLink) -> String {
+    let branch = file_appender
+        .source_branch
+        .clone()
+        .unwrap_or("master".to_owned());
+    if branch == "master" && repo.find_reference("refs/heads/master").is_ok() {
+        return "refs/heads/master".to_owned();
+    }
+    format!("refs/remotes/http-origin/{}", branch)
+}
+
 pub fn get_from_appender(
     file_appender: &GitLink,
     repo: &Repository,
     repo_file_path: &String,
-) -> Vec<Vec<u8>> {
-    let source_branch = "http-origin/".to_owned()
-        + &file_appender
-            .clone()
-            .source_branch
-            .unwrap_or("master".to_owned());
-
-    let content = get_blob_from_head(repo, repo_file_path, source_branch.to_owned());
+) -> Result<(Vec<Vec<u8>>, LineEnding), String> {
+    let content = get_blob_from_head(repo, repo_file_path, &link_reference(repo, file_appender))?;
+    read_lines(file_appender, content)
+}
 
+/// The lines of a file of the repository, decrypted if the link has a password.
+pub fn read_lines(
+    file_appender: &GitLink,
+    content: Vec<u8>,
+) -> Result<(Vec<Vec<u8>>, LineEnding), String> {
     if let Some(password_file) = file_appender.clone().password_file {
         let ro_contents = content;
-        let passphrase = get_file_contents(&password_file).unwrap();
         if ro_contents.is_empty() {
-            Vec::new()
+            Ok((Vec::new(), LineEnding::default()))
         } else {
-            decrypt(
-                ro_contents,
-                String::from_utf8(passphrase).unwrap().into_boxed_str(),
-            )
+            decrypt(ro_contents, read_passphrase(&password_file)?)
         }
     } else {
-        let res = content.split(|c| c == &b'\n');
-        res.map(|s| s.into()).collect()
+        Ok(split_lines(&content))
     }
 }
 
+/// The passphrase of an encrypted link.
+pub fn read_passphrase(password_file: &String) -> Result<Box<str>, String> {
+    let passphrase = get_file_contents(password_file)
+        .map_err(|e| format!("Could not read {}: {}", password_file, e))?;
+    String::from_utf8(passphrase)
+        .map(String::into_boxed_str)
+        .map_err(|_| format!("{} is not valid UTF-8", password_file))
+}
+
+/// Result of a merge, `None` when a side is already up to date.
+#[derive(Debug, Eq, PartialEq)]
+pub struct Merge {
+    pub local: Option<Vec<u8>>,
+    pub remote: Option<Vec<u8>>,
+    pub lines_added_locally: usize,
+    pub lines_added_remotely: usize,
+}
+
+/// Merges both sides into a sorted set of unique lines.
+/// Lines are compared as raw bytes, without any UTF-8 requirement.
+/// Lines dropped by `remove_lines`, `exclude_patterns` or because they only contain NUL chars are reported.
 pub fn append(
     remote_file: Vec<Vec<u8>>,
     local_file: Vec<Vec<u8>>,
@@ -44,24 +75,26 @@ pub fn append(
     exclude_patterns: HashSet<String>,
     features: HashSet<Feature>,
 ) -> (Option<Vec<u8>>, Option<Vec<u8>>) {
-    let local_hash_set = BTreeSet::from_iter(
-        apply_feature_rmb(
-            local_file,
-            features.contains(&Feature::RemoveMultilinesBash),
-        )
-        .into_iter()
-        .filter(|line| !line.is_empty())
-        .clone(),
-    );
-    let remote_hash_set = BTreeSet::from_iter(
-        apply_feature_rmb(
-            remote_file,
-            features.contains(&Feature::RemoveMultilinesBash),
-        )
-        .into_iter()
-        .filter(|line| !line.is_empty())
-        .clone(),
-    );
+    let merge = merge(
+        remote_file,
+        local_file,
+        remove_lines,
+        exclude_patterns,
+        features,
+    )
+    .unwrap_or_else(|e| panic!("{}", e));
+    (merge.local, merge.remote)
+}
+
+pub fn merge(
+    remote_file: Vec<Vec<u8>>,
+    local_file: Vec<Vec<u8>>,
+    remove_lines: HashSet<String>,
+    exclude_patterns: HashSet<String>,
+    features: HashSet<Feature>,
+) -> Result<Merge, String> {
+    let local_hash_set = line_set(local_file, &features);
+    let remote_hash_set = line_set(remote_file, &features);
 
     let mut sum = BTreeSet::new();
 
@@ -70,32 +103,30 @@ pub fn append(
 
     let exclude_patterns: Vec<Regex> = exclude_patterns
         .into_iter()
-        .map(|ep| Regex::new(&ep).expect(&format!("Fail to read regex: {}", ep)))
-        .collect();
+        .map(|ep| Regex::new(&ep).map_err(|_| format!("Fail to read regex: {}", ep)))
+        .collect::<Result<_, _>>()?;
 
     let rm_lines_bytes: Vec<Vec<u8>> = remove_lines
         .into_iter()
         .map(|line| line.as_bytes().to_owned())
         .collect();
-    sum = sum
-        .into_iter()
-        .filter(|line| !line.is_empty())
-        .filter(|line| !rm_lines_bytes.contains(line))
-        .filter(|line| !line.iter().all(|c| c == &0u8))
-        .filter(|line| str::from_utf8(line).is_ok())
-        .filter(|line| {
-            let str = str::from_utf8(line);
-            match str {
-                Ok(new_string) => !exclude_patterns.iter().any(|re| re.is_match(new_string)),
-                Err(_) => false,
-            }
-        })
-        .collect();
 
-    // if local_hash_set == remote_hash_set {
-    //     println!("No changes");
-    //     return (None, None);
-    // }
+    let mut filtered = Filtered::default();
+    sum.retain(|line| {
+        if rm_lines_bytes.contains(line) {
+            filtered.removed_lines += 1;
+            false
+        } else if line.iter().all(|c| c == &0u8) {
+            filtered.nul_lines += 1;
+            false
+        } else if exclude_patterns.iter().any(|re| re.is_match(line)) {
+            filtered.excluded_lines += 1;
+            false
+        } else {
+            true
+        }
+    });
+    filtered.report();
 
     let joined_sum: Vec<Vec<u8>> = sum.clone().into_iter().collect();
     let sum_with_endline = last_char(joined_sum.join(&b'\n'));
@@ -112,7 +143,45 @@ pub fn append(
         Some(sum_with_endline)
     };
 
-    (local_result, remote_result)
+    Ok(Merge {
+        local: local_result,
+        remote: remote_result,
+        lines_added_locally: sum.difference(&local_hash_set).count(),
+        lines_added_remotely: sum.difference(&remote_hash_set).count(),
+    })
+}
+
+/// The unique non empty lines of a file, once the features are applied.
+pub fn line_set(content: Vec<Vec<u8>>, features: &HashSet<Feature>) -> BTreeSet<Vec<u8>> {
+    BTreeSet::from_iter(
+        apply_feature_rmb(content, features.contains(&Feature::RemoveMultilinesBash))
+            .into_iter()
+            .filter(|line| !line.is_empty()),
+    )
+}
+
+#[derive(Debug, Default)]
+struct Filtered {
+    removed_lines: usize,
+    nul_lines: usize,
+    excluded_lines: usize,
+}
+
+impl Filtered {
+    fn report(&self) {
+        if self.removed_lines > 0 {
+            log!("Filtered {} line(s) from remove_lines", self.removed_lines);
+        }
+        if self.nul_lines > 0 {
+            log!("Filtered {} line(s) only made of NUL chars", self.nul_lines);
+        }
+        if self.excluded_lines > 0 {
+            log!(
+                "Filtered {} line(s) matching exclude_patterns",
+                self.excluded_lines
+            );
+        }
+    }
 }
 
 fn last_char(mut content: Vec<u8>) -> Vec<u8> {
@@ -137,7 +206,7 @@ fn feature_remove_multilines_bash(content: Vec<Vec<u8>>) -> Vec<Vec<u8>> {
         .fold(
             (Vec::new(), None) as (Vec<Vec<u8>>, Option<Vec<u8>>),
             |(mut acc, maybe_line), mut current| {
-                let current_is_multi = current.ends_with(&[b'\\']);
+                let current_is_multi = current.ends_with(b"\\");
                 if current_is_multi {
                     current.remove(current.len() - 1);
                     current.push(b' ');
@@ -170,7 +239,7 @@ pub mod tests {
     use pretty_assertions::assert_eq;
 
     use crate::{
-        appender::{append, feature_remove_multilines_bash},
+        appender::{append, feature_remove_multilines_bash, merge, Merge},
         file::get_file_contents_as_lines,
     };
 
@@ -289,9 +358,46 @@ pub mod tests {
         );
     }
 
+    #[test]
+    fn test_merge_counts() {
+        assert_eq!(
+            Merge {
+                local: Some(vec![b'a', b'\n', b'b', b'\n', b'c', b'\n']),
+                remote: Some(vec![b'a', b'\n', b'b', b'\n', b'c', b'\n']),
+                lines_added_locally: 1,
+                lines_added_remotely: 1,
+            },
+            merge(
+                vec![vec![b'a'], vec![b'b']],
+                vec![vec![b'c'], vec![b'b'], vec![b'd']],
+                vec![String::from("d")].into_iter().collect(),
+                HashSet::new(),
+                HashSet::new(),
+            )
+            .unwrap()
+        );
+    }
+
+    #[test]
+    fn test_content_non_utf8() {
+        assert_eq!(
+            (
+                Some(vec![b'a', b'\n', 0xff, 0xfe, b'\n']),
+                Some(vec![b'a', b'\n', 0xff, 0xfe, b'\n'])
+            ),
+            append(
+                vec![vec![b'a'], vec![0, 0, 0]],
+                vec![vec![0xff, 0xfe], vec![0xff, b'x']],
+                HashSet::new(),
+                vec![String::from("(?-u)^\\xffx$")].into_iter().collect(),
+                HashSet::new(),
+            )
+        );
+    }
+
     #[test]
     fn test_remove_multilines_feature() {
-        let input = get_file_contents_as_lines(&String::from("tests/multilines")).unwrap();
+        let (input, _) = get_file_contents_as_lines(&String::from("tests/multilines")).unwrap();
 
         let result = vec![
             ": this is a multiline command".as_bytes().to_owned(),
@@ -1,43 +1,584 @@
+use schemars::JsonSchema;
 use serde::{Deserialize, Serialize};
 use std::collections::{HashMap, HashSet};
+use std::env;
 
-#[derive(Debug, Deserialize, Serialize, Clone, Eq, PartialEq, Hash)]
+use crate::file::expand_path;
+
+pub fn hostname() -> String {
+    gethostname::gethostname().to_string_lossy().into_owned()
+}
+
+/// `$XDG_DATA_HOME`, or its default `~/.local/share`.
+pub fn data_home() -> Result<String, String> {
+    match env::var("XDG_DATA_HOME") {
+        Ok(data_home) if !data_home.is_empty() => Ok(data_home),
+        _ => expand_path("~/.local/share"),
+    }
+}
+
+/// JSON Schema of the config format, for editors.
+pub fn schema() -> serde_json::Value {
+    serde_json::to_value(schemars::schema_for!(Config)).unwrap()
+}
+
+#[derive(
+    Debug, Deserialize, Serialize, JsonSchema, Clone, Eq, PartialEq, Hash, clap::ValueEnum,
+)]
 pub enum Feature {
     RemoveMultilinesBash,
 }
 
-#[derive(Debug, Deserialize, Serialize, Clone, Eq, PartialEq)]
+#[derive(Debug, Deserialize, Serialize, JsonSchema, Clone, Eq, PartialEq, Default)]
 #[serde(deny_unknown_fields)]
 pub struct Config {
+    /// Keyed by the location of the git repository.
+    #[serde(default)]
+    pub appenders: HashMap<String, GitAppender>,
+    /// Merged into every link of every appender.
+    pub defaults: Option<LinkDefaults>,
+    /// Named sets of link values, used from a link with `template`.
+    #[serde(default)]
+    pub templates: HashMap<String, LinkDefaults>,
+    /// Other config files merged before this one, relative to this file.
+    #[serde(default)]
+    pub include: Vec<String>,
+    /// Overlays merged into the config when the hostname matches the key.
+    #[serde(default)]
+    pub hosts: HashMap<String, HostConfig>,
+    /// Timeouts, retries and proxy used to reach every remote.
+    pub network: Option<Network>,
+}
+
+#[derive(Debug, Deserialize, Serialize, JsonSchema, Clone, Eq, PartialEq, Default)]
+#[serde(deny_unknown_fields)]
+pub struct HostConfig {
+    #[serde(default)]
     pub appenders: HashMap<String, GitAppender>,
+    pub defaults: Option<LinkDefaults>,
+    pub network: Option<Network>,
+}
+
+/// Timeouts apply to the whole process, as libgit2 has no per-remote timeout.
+#[derive(Debug, Deserialize, Serialize, JsonSchema, Clone, Eq, PartialEq, Default)]
+#[serde(deny_unknown_fields)]
+pub struct Network {
+    /// Seconds to wait for a connection to a remote, defaults to 30.
+    pub connect_timeout: Option<u64>,
+    /// Seconds to wait for data from a remote during a transfer, defaults to 60.
+    pub timeout: Option<u64>,
+    /// Attempts after a fetch or a push could not reach the remote, waiting 1, 2, 4... seconds, defaults to 3.
+    pub retries: Option<u32>,
+    /// Proxy URL for https remotes, defaults to `http.proxy` of the repository, then to `HTTPS_PROXY`.
+    pub proxy: Option<String>,
+    /// CA certificates (PEM) trusted in addition to the system ones.
+    pub ca_file: Option<String>,
+}
+
+impl Network {
+    pub const DEFAULT_CONNECT_TIMEOUT: u64 = 30;
+    pub const DEFAULT_TIMEOUT: u64 = 60;
+    pub const DEFAULT_RETRIES: u32 = 3;
+
+    fn or(self, other: Network) -> Network {
+        Network {
+            connect_timeout: self.connect_timeout.or(other.connect_timeout),
+            timeout: self.timeout.or(other.timeout),
+            retries: self.retries.or(other.retries),
+            proxy: self.proxy.or(other.proxy),
+            ca_file: self.ca_file.or(other.ca_file),
+        }
+    }
+
+    fn expand_paths(self) -> Result<Network, String> {
+        Ok(Network {
+            ca_file: self.ca_file.map(|f| expand_path(&f)).transpose()?,
+            ..self
+        })
+    }
 }
 
-#[derive(Debug, Deserialize, Serialize, Clone, Eq, PartialEq)]
+#[derive(Debug, Deserialize, Serialize, JsonSchema, Clone, Eq, PartialEq, Default)]
 #[serde(deny_unknown_fields)]
 pub struct GitAppender {
     pub git_config: Option<GitConfig>,
-    #[serde(default = "HashMap::new")]
+    /// Use a bare repository, commits are made without any working tree.
+    /// With a plain name as key, the repository is kept in `$XDG_DATA_HOME/git-append/<name>`.
+    pub bare: Option<bool>,
+    /// URL of `http-origin`, used to create a bare repository on its first run.
+    pub url: Option<String>,
+    /// SHA-256 fingerprint of the certificate of `http-origin` (as `openssl x509 -fingerprint -sha256` prints it).
+    /// Only this certificate is accepted, even if it is self-signed.
+    pub certificate_sha256: Option<String>,
+    /// Other remotes, keyed by name, pushed to after `http-origin` (which is the only one pulled from).
+    /// A mirror failing does not fail the appender.
+    #[serde(default)]
+    pub mirrors: HashMap<String, Mirror>,
+    /// Keyed by the local file location.
+    #[serde(default)]
     pub links: HashMap<String, GitLink>,
-    #[serde(default = "HashMap::new")]
+    /// Keyed by the local folder location, every file under it is linked.
+    #[serde(default)]
     pub folder_links: HashMap<String, GitLink>,
+    /// Merged into every link of this appender, on top of the top-level `defaults`.
+    pub defaults: Option<LinkDefaults>,
+    /// Commit even if changes unrelated to git-append are staged, they are then committed too.
+    pub allow_staged_changes: Option<bool>,
+    /// Author and committer of the commits, `user.name` and `user.email` of the repository are used otherwise.
+    pub author: Option<Author>,
+    /// What to do when the local branch has diverged from the remote, defaults to `abort`.
+    pub pull_policy: Option<PullPolicy>,
+    /// Signs the commits, also done when the repository has `commit.gpgsign`.
+    pub signing: Option<Signing>,
+    /// Template of the commit messages, with `{hostname}`, `{user}`, `{appender}`, `{paths}`, `{changes}` and `{timestamp}`.
+    pub commit_message: Option<String>,
 }
 
-#[derive(Debug, Deserialize, Serialize, Clone, Eq, PartialEq)]
+#[derive(Debug, Deserialize, Serialize, JsonSchema, Clone, Eq, PartialEq)]
 #[serde(deny_unknown_fields)]
 pub struct GitLink {
+    /// Path of the file (or folder) in the git repository.
     pub source_path: String,
+    /// Defaults to `master`.
     pub source_branch: Option<String>,
     // pub Option<sorted>: bool
     // pub Option<unique>: bool
+    /// When set, the file is encrypted in the repository with the passphrase in this file.
     pub password_file: Option<String>,
+    /// Lines removed from both sides.
     pub remove_lines: Option<HashSet<String>>,
+    /// Regexes of lines removed from both sides.
     pub exclude_patterns: Option<HashSet<String>>,
     pub features: Option<HashSet<Feature>>,
+    /// Name of an entry of `templates`.
+    pub template: Option<String>,
+    /// Octal mode forced on the local file (e.g. `0600`), the mode of the file is kept otherwise.
+    pub mode: Option<String>,
+    /// Fields that must not be inherited from a template or from defaults.
+    pub unset: Option<HashSet<LinkField>>,
 }
 
-#[derive(Debug, Deserialize, Serialize, Clone, Eq, PartialEq)]
+/// The values of a `GitLink` that can be inherited.
+#[derive(Debug, Deserialize, Serialize, JsonSchema, Clone, Eq, PartialEq, Default)]
+#[serde(deny_unknown_fields)]
+pub struct LinkDefaults {
+    pub source_branch: Option<String>,
+    pub password_file: Option<String>,
+    pub remove_lines: Option<HashSet<String>>,
+    pub exclude_patterns: Option<HashSet<String>>,
+    pub features: Option<HashSet<Feature>>,
+    pub mode: Option<String>,
+}
+
+#[derive(Debug, Deserialize, Serialize, JsonSchema, Clone, Eq, PartialEq, Hash)]
+#[serde(rename_all = "snake_case")]
+pub enum LinkField {
+    SourceBranch,
+    PasswordFile,
+    RemoveLines,
+    ExcludePatterns,
+    Features,
+    Mode,
+}
+
+#[derive(Debug, Deserialize, Serialize, JsonSchema, Clone, Eq, PartialEq)]
 #[serde(deny_unknown_fields)]
 pub struct GitConfig {
     pub username: String,
+    /// File containing the token used as HTTP password.
     pub token_file: String,
 }
+
+#[derive(Debug, Deserialize, Serialize, JsonSchema, Clone, Eq, PartialEq)]
+#[serde(deny_unknown_fields)]
+pub struct Mirror {
+    pub url: String,
+    pub git_config: Option<GitConfig>,
+    /// SHA-256 fingerprint of the certificate of the mirror, like `certificate_sha256` of the appender.
+    pub certificate_sha256: Option<String>,
+}
+
+/// A missing value falls back to the repository config, then to a value made from the hostname.
+#[derive(Debug, Deserialize, Serialize, JsonSchema, Clone, Eq, PartialEq)]
+#[serde(deny_unknown_fields)]
+pub struct Author {
+    pub name: Option<String>,
+    pub email: Option<String>,
+}
+
+#[derive(Debug, Deserialize, Serialize, JsonSchema, Clone, Eq, PartialEq, Default)]
+#[serde(rename_all = "lowercase")]
+pub enum PullPolicy {
+    /// Fail the appender, to be fixed by hand.
+    #[default]
+    Abort,
+    /// Merge the remote branch with a merge commit.
+    Merge,
+    /// Replay the local commits on the remote branch.
+    Rebase,
+}
+
+/// A missing value is read from `gpg.format` and `user.signingkey` of the repository.
+#[derive(Debug, Deserialize, Serialize, JsonSchema, Clone, Eq, PartialEq)]
+#[serde(deny_unknown_fields)]
+pub struct Signing {
+    pub format: Option<SigningFormat>,
+    /// OpenPGP key id, or SSH public key file (or `key::` followed by the public key, its private key being in the SSH agent).
+    pub key: Option<String>,
+}
+
+#[derive(Debug, Deserialize, Serialize, JsonSchema, Clone, Eq, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum SigningFormat {
+    Openpgp,
+    Ssh,
+}
+
+impl Config {
+    /// Merges templates and defaults into every link.
+    /// A link value wins over its template, which wins over the appender defaults, then the top-level defaults.
+    /// A bare appender with a plain name is placed in `$XDG_DATA_HOME/git-append`, once includes and hosts are merged.
+    pub fn resolve_defaults(self) -> Result<Config, String> {
+        let top_defaults = self.defaults.unwrap_or_default();
+        let templates = self.templates;
+        Ok(Config {
+            appenders: self
+                .appenders
+                .into_iter()
+                .map(|(git_folder, appender)| {
+                    let defaults = appender
+                        .defaults
+                        .clone()
+                        .unwrap_or_default()
+                        .or(&top_defaults);
+                    let resolve_links = |links: HashMap<String, GitLink>| {
+                        links
+                            .into_iter()
+                            .map(|(path, link)| {
+                                let inherited = match &link.template {
+                                    Some(name) => templates
+                                        .get(name)
+                                        .ok_or(format!("Unknown template {} for {}", name, path))?
+                                        .clone()
+                                        .or(&defaults),
+                                    None => defaults.clone(),
+                                };
+                                Ok((path, link.inherit(inherited)))
+                            })
+                            .collect::<Result<HashMap<_, _>, String>>()
+                    };
+                    let git_folder = if appender.bare == Some(true) && is_plain_name(&git_folder) {
+                        format!("{}/git-append/{}", data_home()?, git_folder)
+                    } else {
+                        git_folder
+                    };
+                    Ok((
+                        git_folder,
+                        GitAppender {
+                            links: resolve_links(appender.links)?,
+                            folder_links: resolve_links(appender.folder_links)?,
+                            defaults: None,
+                            ..appender
+                        },
+                    ))
+                })
+                .collect::<Result<_, String>>()?,
+            defaults: None,
+            templates: HashMap::new(),
+            include: Vec::new(),
+            hosts: HashMap::new(),
+            network: self.network,
+        })
+    }
+
+    /// The appender of a resolved config, from its location as given on the command line.
+    pub fn find_appender(
+        &self,
+        repository_location: &str,
+    ) -> Result<(&String, &GitAppender), String> {
+        let mut locations = vec![
+            repository_location.to_owned(),
+            expand_path(repository_location)?,
+        ];
+        if is_plain_name(repository_location) {
+            locations.push(format!(
+                "{}/git-append/{}",
+                data_home()?,
+                repository_location
+            ));
+        }
+        locations
+            .iter()
+            .find_map(|location| self.appenders.get_key_value(location))
+            .ok_or(format!("{} is not in the config", repository_location))
+    }
+
+    /// Expands `~` and environment variables in every path of the config.
+    pub fn expand_paths(self) -> Result<Config, String> {
+        Ok(Config {
+            appenders: expand_appenders(self.appenders)?,
+            defaults: self.defaults.map(|d| d.expand_paths()).transpose()?,
+            templates: self
+                .templates
+                .into_iter()
+                .map(|(name, template)| Ok((name, template.expand_paths()?)))
+                .collect::<Result<_, String>>()?,
+            include: self
+                .include
+                .iter()
+                .map(|path| expand_path(path))
+                .collect::<Result<_, String>>()?,
+            hosts: self
+                .hosts
+                .into_iter()
+                .map(|(name, host)| {
+                    Ok((
+                        name,
+                        HostConfig {
+                            appenders: expand_appenders(host.appenders)?,
+                            defaults: host.defaults.map(|d| d.expand_paths()).transpose()?,
+                            network: host.network.map(|n| n.expand_paths()).transpose()?,
+                        },
+                    ))
+                })
+                .collect::<Result<_, String>>()?,
+            network: self.network.map(|n| n.expand_paths()).transpose()?,
+        })
+    }
+
+    /// Merges `other` into this config, values from `other` win.
+    pub fn merge(self, other: Config) -> Config {
+        let mut hosts = self.hosts;
+        for (name, host) in other.hosts {
+            let current = hosts.remove(&name).unwrap_or_default();
+            hosts.insert(
+                name,
+                HostConfig {
+                    appenders: merge_appenders(current.appenders, host.appenders),
+                    defaults: merge_defaults(current.defaults, host.defaults),
+                    network: merge_network(current.network, host.network),
+                },
+            );
+        }
+        let mut templates = self.templates;
+        templates.extend(other.templates);
+        Config {
+            appenders: merge_appenders(self.appenders, other.appenders),
+            defaults: merge_defaults(self.defaults, other.defaults),
+            templates,
+            include: Vec::new(),
+            hosts,
+            network: merge_network(self.network, other.network),
+        }
+    }
+
+    /// Merges the overlay of `hostname` (if any) and drops all the other overlays.
+    pub fn apply_host(mut self, hostname: &str) -> Config {
+        let host = self.hosts.remove(hostname).unwrap_or_default();
+        Config {
+            hosts: HashMap::new(),
+            ..self
+        }
+        .merge(Config {
+            appenders: host.appenders,
+            defaults: host.defaults,
+            network: host.network,
+            ..Config::default()
+        })
+    }
+}
+
+fn expand_appenders(
+    appenders: HashMap<String, GitAppender>,
+) -> Result<HashMap<String, GitAppender>, String> {
+    appenders
+        .into_iter()
+        .map(|(git_folder, appender)| {
+            // whether a plain name is bare is only known once merged, see `resolve_defaults`
+            let git_folder = if is_plain_name(&git_folder) {
+                git_folder
+            } else {
+                expand_path(&git_folder)?
+            };
+            Ok((git_folder, appender.expand_paths()?))
+        })
+        .collect()
+}
+
+/// An appender key that is a name rather than a path.
+fn is_plain_name(git_folder: &str) -> bool {
+    !git_folder.contains(['/', '~', '$'])
+}
+
+fn merge_appenders(
+    mut appenders: HashMap<String, GitAppender>,
+    other: HashMap<String, GitAppender>,
+) -> HashMap<String, GitAppender> {
+    for (git_folder, appender) in other {
+        let merged = match appenders.remove(&git_folder) {
+            Some(current) => current.merge(appender),
+            None => appender,
+        };
+        appenders.insert(git_folder, merged);
+    }
+    appenders
+}
+
+fn merge_defaults(
+    defaults: Option<LinkDefaults>,
+    other: Option<LinkDefaults>,
+) -> Option<LinkDefaults> {
+    match (defaults, other) {
+        (Some(defaults), Some(other)) => Some(other.or(&defaults)),
+        (defaults, other) => other.or(defaults),
+    }
+}
+
+fn merge_network(network: Option<Network>, other: Option<Network>) -> Option<Network> {
+    match (network, other) {
+        (Some(network), Some(other)) => Some(other.or(network)),
+        (network, other) => other.or(network),
+    }
+}
+
+impl GitAppender {
+    fn merge(mut self, other: GitAppender) -> GitAppender {
+        self.links.extend(other.links);
+        self.folder_links.extend(other.folder_links);
+        self.mirrors.extend(other.mirrors);
+        GitAppender {
+            git_config: other.git_config.or(self.git_config),
+            bare: other.bare.or(self.bare),
+            url: other.url.or(self.url),
+            certificate_sha256: other.certificate_sha256.or(self.certificate_sha256),
+            defaults: merge_defaults(self.defaults, other.defaults),
+            allow_staged_changes: other.allow_staged_changes.or(self.allow_staged_changes),
+            commit_message: other.commit_message.or(self.commit_message),
+            author: other.author.or(self.author),
+            signing: other.signing.or(self.signing),
+            pull_policy: other.pull_policy.or(self.pull_policy),
+            ..self
+        }
+    }
+
+    fn expand_paths(self) -> Result<GitAppender, String> {
+        let expand_links = |links: HashMap<String, GitLink>| {
+            links
+                .into_iter()
+                .map(|(path, link)| Ok((expand_path(&path)?, link.expand_paths()?)))
+                .collect::<Result<HashMap<_, _>, String>>()
+        };
+        Ok(GitAppender {
+            git_config: self.git_config.map(|c| c.expand_paths()).transpose()?,
+            signing: self.signing.map(|s| s.expand_paths()).transpose()?,
+            mirrors: self
+                .mirrors
+                .into_iter()
+                .map(|(name, mirror)| {
+                    Ok((
+                        name,
+                        Mirror {
+                            git_config: mirror.git_config.map(|c| c.expand_paths()).transpose()?,
+                            ..mirror
+                        },
+                    ))
+                })
+                .collect::<Result<_, String>>()?,
+            links: expand_links(self.links)?,
+            folder_links: expand_links(self.folder_links)?,
+            ..self
+        })
+    }
+}
+
+impl GitLink {
+    fn inherit(self, defaults: LinkDefaults) -> GitLink {
+        let unset = self.unset.unwrap_or_default();
+        let pick = |field: LinkField| !unset.contains(&field);
+        GitLink {
+            source_branch: self
+                .source_branch
+                .or(defaults.source_branch)
+                .filter(|_| pick(LinkField::SourceBranch)),
+            password_file: self
+                .password_file
+                .or(defaults.password_file)
+                .filter(|_| pick(LinkField::PasswordFile)),
+            remove_lines: self
+                .remove_lines
+                .or(defaults.remove_lines)
+                .filter(|_| pick(LinkField::RemoveLines)),
+            exclude_patterns: self
+                .exclude_patterns
+                .or(defaults.exclude_patterns)
+                .filter(|_| pick(LinkField::ExcludePatterns)),
+            features: self
+                .features
+                .or(defaults.features)
+                .filter(|_| pick(LinkField::Features)),
+            mode: self
+                .mode
+                .or(defaults.mode)
+                .filter(|_| pick(LinkField::Mode)),
+            template: None,
+            unset: None,
+            ..self
+        }
+    }
+
+    fn expand_paths(self) -> Result<GitLink, String> {
+        Ok(GitLink {
+            password_file: self.password_file.map(|p| expand_path(&p)).transpose()?,
+            ..self
+        })
+    }
+}
+
+impl Signing {
+    fn expand_paths(self) -> Result<Signing, String> {
+        Ok(Signing {
+            key: self
+                .key
+                .map(|k| {
+                    if k.starts_with("key::") {
+                        Ok(k)
+                    } else {
+                        expand_path(&k)
+                    }
+                })
+                .transpose()?,
+            ..self
+        })
+    }
+}
+
+impl GitConfig {
+    fn expand_paths(self) -> Result<GitConfig, String> {
+        Ok(GitConfig {
+            token_file: expand_path(&self.token_file)?,
+            ..self
+        })
+    }
+}
+
+impl LinkDefaults {
+    fn expand_paths(self) -> Result<LinkDefaults, String> {
+        Ok(LinkDefaults {
+            password_file: self.password_file.map(|p| expand_path(&p)).transpose()?,
+            ..self
+        })
+    }
+
+    /// Fills the missing values from `other`.
+    fn or(self, other: &LinkDefaults) -> LinkDefaults {
+        let other = other.clone();
+        LinkDefaults {
+            source_branch: self.source_branch.or(other.source_branch),
+            password_file: self.password_file.or(other.password_file),
+            remove_lines: self.remove_lines.or(other.remove_lines),
+            exclude_patterns: self.exclude_patterns.or(other.exclude_patterns),
+            features: self.features.or(other.features),
+            mode: self.mode.or(other.mode),
+        }
+    }
+}
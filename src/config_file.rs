@@ -0,0 +1,260 @@
+use std::fs;
+use std::path::Path;
+
+use serde_json::{Map, Value};
+
+use crate::config::{GitConfig, GitLink};
+
+/// Reads a config file as a document, to edit it without losing the order of its keys.
+/// A missing file is an empty config.
+pub fn read_document(path: &Path) -> Result<Value, String> {
+    if !path.exists() {
+        return Ok(serde_json::json!({ "appenders": {} }));
+    }
+    let content =
+        fs::read_to_string(path).map_err(|e| format!("Cannot open {}: {}", path.display(), e))?;
+    let invalid_format = |e: String| format!("Invalid format {}: {}", path.display(), e);
+    match path.extension().and_then(|e| e.to_str()) {
+        Some("toml") => toml::from_str(&content).map_err(|e| invalid_format(e.to_string())),
+        Some("yaml") | Some("yml") => {
+            serde_yaml::from_str(&content).map_err(|e| invalid_format(e.to_string()))
+        }
+        _ => serde_json::from_str(&content).map_err(|e| invalid_format(e.to_string())),
+    }
+}
+
+/// YAML and TOML configs are written back without their comments, they are only edited when they have none.
+pub fn check_editable(path: &Path) -> Result<(), String> {
+    let commented = matches!(
+        path.extension().and_then(|e| e.to_str()),
+        Some("toml") | Some("yaml") | Some("yml")
+    );
+    if !commented || !path.exists() {
+        return Ok(());
+    }
+    let content =
+        fs::read_to_string(path).map_err(|e| format!("Cannot open {}: {}", path.display(), e))?;
+    if content.contains('#') {
+        return Err(format!(
+            "{} has comments, they would be lost: edit it by hand",
+            path.display()
+        ));
+    }
+    Ok(())
+}
+
+/// Writes the document back, in the format given by the extension.
+pub fn write_document(path: &Path, document: &Value) -> Result<(), String> {
+    let content = match path.extension().and_then(|e| e.to_str()) {
+        Some("toml") => toml::to_string(document).map_err(|e| e.to_string())?,
+        Some("yaml") | Some("yml") => serde_yaml::to_string(document).map_err(|e| e.to_string())?,
+        _ => serde_json::to_string_pretty(document).map_err(|e| e.to_string())? + "\n",
+    };
+    if let Some(folder) = path.parent().filter(|f| !f.as_os_str().is_empty()) {
+        fs::create_dir_all(folder).map_err(|e| e.to_string())?;
+    }
+    fs::write(path, content).map_err(|e| format!("Cannot write {}: {}", path.display(), e))
+}
+
+pub fn add_appender(
+    document: &mut Value,
+    git_folder: &str,
+    git_config: Option<GitConfig>,
+    certificate_sha256: Option<String>,
+) -> Result<(), String> {
+    let appenders = object_entry(document, "appenders")?;
+    if appenders.contains_key(git_folder) {
+        return Err(format!("{} is already in the config", git_folder));
+    }
+    let mut appender = Map::new();
+    if let Some(git_config) = git_config {
+        appender.insert("git_config".to_owned(), to_document(&git_config));
+    }
+    if let Some(certificate_sha256) = certificate_sha256 {
+        appender.insert(
+            "certificate_sha256".to_owned(),
+            Value::String(certificate_sha256),
+        );
+    }
+    appenders.insert(git_folder.to_owned(), Value::Object(appender));
+    Ok(())
+}
+
+/// Adds to `links`, or to `folder_links` when `folder` is set.
+pub fn add_link(
+    document: &mut Value,
+    git_folder: &str,
+    folder: bool,
+    local_path: &str,
+    link: &GitLink,
+) -> Result<(), String> {
+    let links = object_entry(appender_entry(document, git_folder)?, links_key(folder))?;
+    if links.contains_key(local_path) {
+        return Err(format!(
+            "{} is already linked in {}",
+            local_path, git_folder
+        ));
+    }
+    links.insert(local_path.to_owned(), to_document(link));
+    Ok(())
+}
+
+/// Removes from `links`, or from `folder_links` when `folder` is set.
+pub fn remove_link(
+    document: &mut Value,
+    git_folder: &str,
+    folder: bool,
+    local_path: &str,
+) -> Result<(), String> {
+    object_entry(appender_entry(document, git_folder)?, links_key(folder))?
+        .shift_remove(local_path)
+        .map(|_| ())
+        .ok_or(format!("{} is not linked in {}", local_path, git_folder))
+}
+
+fn links_key(folder: bool) -> &'static str {
+    if folder {
+        "folder_links"
+    } else {
+        "links"
+    }
+}
+
+fn appender_entry<'a>(document: &'a mut Value, git_folder: &str) -> Result<&'a mut Value, String> {
+    object_entry(document, "appenders")?
+        .get_mut(git_folder)
+        .ok_or(format!("{} is not in the config", git_folder))
+}
+
+/// The object under `key`, created when missing.
+fn object_entry<'a>(value: &'a mut Value, key: &str) -> Result<&'a mut Map<String, Value>, String> {
+    value
+        .as_object_mut()
+        .ok_or("Expected an object".to_owned())?
+        .entry(key)
+        .or_insert(Value::Object(Map::new()))
+        .as_object_mut()
+        .ok_or(format!("Expected `{}` to be an object", key))
+}
+
+/// Serializes without the unset (`null`) values.
+fn to_document<T: serde::Serialize>(value: &T) -> Value {
+    match serde_json::to_value(value).unwrap() {
+        Value::Object(map) => {
+            Value::Object(map.into_iter().filter(|(_, v)| !v.is_null()).collect())
+        }
+        value => value,
+    }
+}
+
+#[cfg(test)]
+pub mod tests {
+    use pretty_assertions::assert_eq;
+    use std::path::Path;
+
+    use crate::{
+        config::GitLink,
+        config_file::{add_appender, add_link, check_editable, read_document, remove_link},
+    };
+
+    #[test]
+    fn test_edit_keeps_key_order() {
+        let mut document = read_document(Path::new("tests/example-config.json")).unwrap();
+        let link = GitLink {
+            source_path: "new_file_in_git".to_string(),
+            password_file: Some(String::from("/home/password-file")),
+            source_branch: None,
+            remove_lines: None,
+            exclude_patterns: None,
+            features: None,
+            mode: None,
+            template: None,
+            unset: None,
+        };
+        add_link(
+            &mut document,
+            "/home/some/other/repository-location",
+            false,
+            "/new_file",
+            &link,
+        )
+        .unwrap();
+        add_appender(
+            &mut document,
+            "/home/new-repository",
+            None,
+            Some("AB:CD".to_owned()),
+        )
+        .unwrap();
+
+        let appender = &document["appenders"]["/home/some/other/repository-location"];
+        assert_eq!(
+            vec!["/plaintext_file", "/new_file"],
+            appender["links"]
+                .as_object()
+                .unwrap()
+                .keys()
+                .collect::<Vec<_>>()
+        );
+        assert_eq!(
+            serde_json::json!({"source_path": "new_file_in_git", "password_file": "/home/password-file"}),
+            appender["links"]["/new_file"]
+        );
+        assert_eq!(
+            vec![
+                "/home/someone/repository-location",
+                "/home/some/other/repository-location",
+                "/home/new-repository"
+            ],
+            document["appenders"]
+                .as_object()
+                .unwrap()
+                .keys()
+                .collect::<Vec<_>>()
+        );
+
+        assert_eq!(
+            serde_json::json!({"certificate_sha256": "AB:CD"}),
+            document["appenders"]["/home/new-repository"]
+        );
+        assert!(add_appender(&mut document, "/home/new-repository", None, None).is_err());
+    }
+
+    #[test]
+    fn test_remove_link() {
+        let mut document = read_document(Path::new("tests/example-config.json")).unwrap();
+        remove_link(
+            &mut document,
+            "/home/some/other/repository-location",
+            true,
+            "/plaintext_folder",
+        )
+        .unwrap();
+        assert_eq!(
+            serde_json::json!({}),
+            document["appenders"]["/home/some/other/repository-location"]["folder_links"]
+        );
+        assert!(remove_link(
+            &mut document,
+            "/home/some/other/repository-location",
+            false,
+            "/plaintext_folder",
+        )
+        .is_err());
+    }
+
+    #[test]
+    fn test_check_editable() {
+        let folder = tempfile::tempdir().unwrap();
+        let write = |name: &str, content: &str| {
+            let path = folder.path().join(name);
+            std::fs::write(&path, content).unwrap();
+            path
+        };
+        assert!(check_editable(&write("commented.yaml", "# hosts\nappenders: {}\n")).is_err());
+        assert!(check_editable(&write("commented.toml", "[appenders] # none\n")).is_err());
+        assert!(check_editable(&write("plain.yaml", "appenders: {}\n")).is_ok());
+        assert!(check_editable(&write("config.json", "{\"appenders\": {\"#\": {}}}")).is_ok());
+        assert!(check_editable(&folder.path().join("missing.yaml")).is_ok());
+    }
+}
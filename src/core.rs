@@ -1,12 +1,23 @@
-use git2::Repository;
+use std::io::{self, BufRead, Write};
+use std::path::Path;
+use std::time::Duration;
+
+use git2::{Oid, Repository};
+use glob::glob;
 
 use crate::{
     age::encrypt,
-    appender::{append, get_from_appender},
-    config::{GitConfig, GitLink},
-    file::{get_file_contents, get_file_contents_strip_final_end_line, write_to_file},
-    git::{open, pull},
-    parse_config,
+    appender::{get_from_appender, link_reference, merge, read_lines, read_passphrase},
+    config::{GitAppender, GitConfig, GitLink, Network},
+    config_file::{add_appender, add_link, check_editable, read_document, write_document},
+    file::{
+        expand_path, file_mode, file_stamp, get_file_contents_strip_final_end_line, parse_mode,
+        read_config_file, set_file_mode, write_to_file, write_to_file_if_unchanged,
+    },
+    git::{self, add_ca_file, commit_entry, open, pull, set_timeouts, tree_entry, RemoteOptions},
+    log, parse_config,
+    report::LinkReport,
+    signing::signer,
 };
 
 pub fn decrypt_file(path: String, repository_location: String, file: String) {
@@ -21,85 +32,492 @@ pub fn decrypt_file(path: String, repository_location: String, file: String) {
         .iter()
         .find(|(_, s)| s.source_path == file)
         .expect("File not in config");
-    let repo = open(&format!("{}/.git", git_folder));
-    let credentials = appender.git_config.clone().map(
-        |GitConfig {
-             username,
-             token_file,
-         }| {
-            (
-                username,
-                String::from_utf8(get_file_contents_strip_final_end_line(&token_file).unwrap())
-                    .unwrap(),
-            )
-        },
-    );
+    let repo = open(git_folder).unwrap_or_else(|e| panic!("{}", e));
+    apply_network(configs.network.as_ref()).unwrap_or_else(|e| panic!("{}", e));
+    let options = remote_options(
+        appender.git_config.clone(),
+        appender.certificate_sha256.clone(),
+        configs.network.as_ref(),
+    )
+    .unwrap_or_else(|e| panic!("{}", e));
     let source_branch = file_appender
         .clone()
         .source_branch
         .unwrap_or("master".to_owned());
-    pull(&repo, credentials, source_branch);
+    let sign = git::signature(&repo, appender.author.as_ref()).unwrap_or_else(|e| panic!("{}", e));
+    let signer = signer(&repo, appender.signing.as_ref()).unwrap_or_else(|e| panic!("{}", e));
+    pull(
+        &repo,
+        &options,
+        source_branch,
+        &appender.pull_policy.clone().unwrap_or_default(),
+        &sign,
+        signer.as_ref(),
+        &|path, local, remote| reconcile(appender, path, local, remote),
+    )
+    .unwrap_or_else(|e| panic!("{}", e));
+}
+
+pub fn read_credentials(git_config: Option<GitConfig>) -> Result<Option<(String, String)>, String> {
+    git_config
+        .map(
+            |GitConfig {
+                 username,
+                 token_file,
+             }| {
+                let token = get_file_contents_strip_final_end_line(&token_file)
+                    .map_err(|e| format!("Could not read {}: {}", token_file, e))?;
+                let token = String::from_utf8(token)
+                    .map_err(|_| format!("{} is not valid UTF-8", token_file))?;
+                Ok((username, token))
+            },
+        )
+        .transpose()
+}
+
+pub fn remote_options(
+    git_config: Option<GitConfig>,
+    certificate_sha256: Option<String>,
+    network: Option<&Network>,
+) -> Result<RemoteOptions, String> {
+    Ok(RemoteOptions {
+        credentials: read_credentials(git_config)?,
+        certificate_sha256,
+        proxy: network.and_then(|n| n.proxy.clone()),
+        retries: network
+            .and_then(|n| n.retries)
+            .unwrap_or(Network::DEFAULT_RETRIES),
+    })
 }
+
+/// Must be called before connecting to any remote.
+pub fn apply_network(network: Option<&Network>) -> Result<(), String> {
+    if let Some(ca_file) = network.and_then(|n| n.ca_file.as_ref()) {
+        add_ca_file(ca_file)?;
+    }
+    set_timeouts(
+        Duration::from_secs(
+            network
+                .and_then(|n| n.connect_timeout)
+                .unwrap_or(Network::DEFAULT_CONNECT_TIMEOUT),
+        ),
+        Duration::from_secs(
+            network
+                .and_then(|n| n.timeout)
+                .unwrap_or(Network::DEFAULT_TIMEOUT),
+        ),
+    );
+    Ok(())
+}
+
+/// Clones the repository (or reuses it if already there), and adds it to the config with an optional first link.
+/// The network settings of the config, if it exists, are used for the clone. Nothing is cloned if the config cannot
+/// take the appender.
+pub fn init(
+    config_path: String,
+    url: String,
+    repository_location: String,
+    git_config: Option<GitConfig>,
+    certificate_sha256: Option<String>,
+    link: Option<(bool, String, GitLink)>,
+) -> Result<(), String> {
+    let git_folder = expand_path(&repository_location)?;
+    let config_path = Path::new(&config_path);
+    let network = if config_path.exists() {
+        let config = read_config_file(&config_path.to_string_lossy())?;
+        if config.find_appender(&repository_location).is_ok() {
+            return Err(format!("{} is already in the config", repository_location));
+        }
+        config.network
+    } else {
+        None
+    };
+    check_editable(config_path)?;
+    let mut document = read_document(config_path)?;
+    add_appender(
+        &mut document,
+        &repository_location,
+        git_config.clone(),
+        certificate_sha256.clone(),
+    )?;
+    if let Some((folder, local_path, link)) = link {
+        add_link(
+            &mut document,
+            &repository_location,
+            folder,
+            &local_path,
+            &link,
+        )?;
+    }
+
+    let options = remote_options(
+        git_config
+            .map(|c| expand_path(&c.token_file).map(|token_file| GitConfig { token_file, ..c }))
+            .transpose()?,
+        certificate_sha256,
+        network.as_ref(),
+    )?;
+    if Path::new(&git_folder).join(".git").exists() {
+        let repo = open(&git_folder)?;
+        if repo.find_remote("http-origin").is_err() {
+            println!("Adding http-origin remote to {}", git_folder);
+            repo.remote("http-origin", &url)
+                .map_err(|e| format!("Could not add http-origin: {}", e.message()))?;
+        }
+    } else {
+        println!("Cloning {} into {}", url, git_folder);
+        apply_network(network.as_ref())?;
+        git::clone(&url, Path::new(&git_folder), &options)
+            .map_err(|e| format!("Could not clone {}: {}", url, e))?;
+    }
+
+    write_document(config_path, &document)?;
+    println!(
+        "Added {} to {}, run `git-append run --config-path={}` to sync it.",
+        repository_location,
+        config_path.display(),
+        config_path.display()
+    );
+    Ok(())
+}
+
+/// Removes from every repository of the config the fetch refspecs added by older versions.
+pub fn cleanup_refspecs(config_path: String) {
+    for git_folder in parse_config(config_path).appenders.keys() {
+        let repo = open(git_folder).unwrap_or_else(|e| panic!("{}", e));
+        let removed = git::remove_added_refspecs(&repo)
+            .unwrap_or_else(|e| panic!("Could not clean {}: {}", git_folder, e));
+        println!(
+            "Removed {} refspec(s) from {}: {:?}",
+            removed.len(),
+            git_folder,
+            removed
+        );
+    }
+}
+
+/// The files of a folder link, as (local path, path in the repository).
+pub fn folder_files(
+    folder: &String,
+    folder_appender: &GitLink,
+) -> Result<Vec<(String, String)>, String> {
+    let files = glob(&format!("{}/**/*", folder))
+        .map_err(|e| format!("Invalid folder {}: {}", folder, e))?
+        .filter_map(|entry| match entry {
+            Ok(path) => {
+                if path.is_file() && !path.to_string_lossy().contains(".git") {
+                    let local_path = path.strip_prefix(folder).unwrap_or(&path);
+                    Some((
+                        format!("{}", path.display()),
+                        format!(
+                            "{}/{}",
+                            folder_appender.source_path.to_owned(),
+                            local_path.display(),
+                        ),
+                    ))
+                } else {
+                    log!("Ignored folder or link: {:?} (or in .git folder)", path);
+                    None
+                }
+            }
+            Err(e) => {
+                log!("Ignored: {:?}", e);
+                None
+            }
+        })
+        .collect();
+    Ok(files)
+}
+
+/// The link a file of the repository belongs to, a plain one if it is no longer linked.
+fn repository_link(appender: &GitAppender, repo_file_path: &str) -> GitLink {
+    let folder_link = || {
+        appender
+            .folder_links
+            .values()
+            .find(|link| repo_file_path.starts_with(&format!("{}/", link.source_path)))
+    };
+    appender
+        .links
+        .values()
+        .find(|link| link.source_path == repo_file_path)
+        .or_else(folder_link)
+        .cloned()
+        .unwrap_or_else(|| GitLink {
+            source_path: repo_file_path.to_owned(),
+            source_branch: None,
+            password_file: None,
+            remove_lines: None,
+            exclude_patterns: None,
+            features: None,
+            mode: None,
+            template: None,
+            unset: None,
+        })
+}
+
+/// Merges the lines of a file queued while offline with the remote one, decrypting and encrypting it again if needed.
+pub fn reconcile(
+    appender: &GitAppender,
+    repo_file_path: &str,
+    local: &[u8],
+    remote: &[u8],
+) -> Result<Vec<u8>, String> {
+    let link = repository_link(appender, repo_file_path);
+    let (local_lines, _) = read_lines(&link, local.to_vec())?;
+    let (remote_lines, line_ending) = read_lines(&link, remote.to_vec())?;
+    let merge = merge(
+        remote_lines,
+        local_lines,
+        link.remove_lines.clone().unwrap_or_default(),
+        link.exclude_patterns.clone().unwrap_or_default(),
+        link.features.clone().unwrap_or_default(),
+    )?;
+    let Some(content) = merge.remote else {
+        return Ok(remote.to_vec());
+    };
+    if merge.local.is_none() {
+        return Ok(local.to_vec());
+    }
+    let content = line_ending.apply(content);
+    match &link.password_file {
+        Some(password_file) => encrypt(&content, read_passphrase(password_file)?),
+        None => Ok(content),
+    }
+}
+
+/// Asks a question on the terminal, an empty answer is `None`.
+pub fn prompt(question: &str) -> Option<String> {
+    print!("{}: ", question);
+    io::stdout().flush().unwrap();
+    let mut answer = String::new();
+    io::stdin().lock().read_line(&mut answer).unwrap();
+    Some(answer.trim().to_owned()).filter(|a| !a.is_empty())
+}
+
 use crate::get_file_contents_as_lines;
-use std::collections::HashSet;
 
+/// How many times a local file modified during its merge is merged again.
+const MAX_MERGE_ATTEMPTS: usize = 5;
+
+/// `synced` is the local branch before the pull, as left by the last sync.
 pub fn process_file(
     file_appender: &GitLink,
     file_path: &String,
     repo_file_path: String,
     git_folder: &String,
     repo: &Repository,
-) -> Vec<String> {
-    println!("Processing: {}", file_path);
-    let mut files = Vec::new();
-    let rm_lines = file_appender.clone().remove_lines.unwrap_or(HashSet::new());
-    let exclude_patterns = file_appender
-        .clone()
-        .exclude_patterns
-        .unwrap_or(HashSet::new());
-    let features = file_appender.clone().features.unwrap_or(HashSet::new());
-    let rw_contents = get_file_contents_as_lines(file_path).unwrap_or(Vec::new());
-    let final_rw_content = rw_contents.clone();
-    let current_ro_content = &mut get_from_appender(file_appender, &repo, &repo_file_path);
-
-    let (local_result, remote_result) = append(
-        current_ro_content.clone(),
-        final_rw_content,
-        rm_lines,
-        exclude_patterns,
-        features,
-    );
-    // println!(
-    //     "Result changed? (local {}, remote {})",
-    //     local_result.is_some(),
-    //     remote_result.is_some()
-    // );
-
-    // if remote_result.is_some() {
-    //     println!("{:?}", remote_result.clone().map(|r| String::from_utf8(r)));
-    // }
-    // if local_result.is_some() {
-    //     println!("{:?}", local_result.clone().map(|r| String::from_utf8(r)));
-    // }
-
-    if let Some(local_content) = local_result {
-        write_to_file(file_path, &local_content.clone());
+    synced: Option<Oid>,
+) -> Result<LinkReport, String> {
+    log!("Processing: {}", file_path);
+    let rm_lines = file_appender.clone().remove_lines.unwrap_or_default();
+    let exclude_patterns = file_appender.clone().exclude_patterns.unwrap_or_default();
+    let features = file_appender.clone().features.unwrap_or_default();
+    let forced_mode = file_appender.mode.as_deref().map(parse_mode).transpose()?;
+    let (current_ro_content, remote_line_ending) =
+        get_from_appender(file_appender, repo, &repo_file_path)?;
+    let remote_entry = tree_entry(repo, &repo_file_path, &link_reference(repo, file_appender));
+    let is_executable = |(_, mode): (Oid, i32)| mode == i32::from(git2::FileMode::BlobExecutable);
+    let remote_executable = remote_entry.map(is_executable);
+    let synced_executable = synced
+        .and_then(|synced| commit_entry(repo, synced, &repo_file_path))
+        .map(is_executable);
+    // a file created locally is private, unless a mode is forced
+    let new_file_mode = match remote_executable {
+        Some(true) => 0o700,
+        _ => 0o600,
+    };
+
+    // the local file can be appended to while merging (by a shell), it is then merged again
+    let mut attempts = 0;
+    let merge = loop {
+        attempts += 1;
+        let stamp = file_stamp(file_path);
+        let (rw_contents, local_line_ending) =
+            get_file_contents_as_lines(file_path).unwrap_or_default();
+        let merge = merge(
+            current_ro_content.clone(),
+            rw_contents,
+            rm_lines.clone(),
+            exclude_patterns.clone(),
+            features.clone(),
+        )?;
+        let Some(local_content) = &merge.local else {
+            break merge;
+        };
+        let content = local_line_ending.apply(local_content.clone());
+        let mode = forced_mode.or(stamp.is_none().then_some(new_file_mode));
+        if write_to_file_if_unchanged(file_path, &content, mode, &stamp)? {
+            break merge;
+        }
+        if attempts == MAX_MERGE_ATTEMPTS {
+            return Err(format!("{} kept changing while being merged", file_path));
+        }
+        log!("{} changed while being merged, merging again", file_path);
+    };
+    if let Some(mode) = forced_mode {
+        set_file_mode(file_path, mode)?;
+    }
+
+    // only the executable bit can be stored in git, a local change since the last sync (or a forced mode) is pushed,
+    // otherwise the mode of the remote is applied to the local file
+    let mut local_executable = file_mode(file_path).map(|mode| mode & 0o111 != 0);
+    let mode_changed = remote_executable.is_some()
+        && local_executable.is_some()
+        && local_executable != remote_executable
+        && (forced_mode.is_some()
+            || (synced_executable.is_some() && local_executable != synced_executable));
+    if let (false, Some(executable), Some(mode)) =
+        (mode_changed, remote_executable, file_mode(file_path))
+    {
+        if local_executable != Some(executable) {
+            let mode = if executable {
+                mode | ((mode & 0o444) >> 2)
+            } else {
+                mode & !0o111
+            };
+            set_file_mode(file_path, mode)?;
+            local_executable = Some(executable);
+        }
+    }
+    let remote_changed = merge.remote.is_some() || mode_changed;
+    let final_ro_content = merge
+        .remote
+        .map(|content_to_encrypt| {
+            let content_to_encrypt = remote_line_ending.apply(content_to_encrypt);
+            match &file_appender.password_file {
+                Some(password_file) => {
+                    encrypt(&content_to_encrypt, read_passphrase(password_file)?)
+                }
+                None => Ok(content_to_encrypt),
+            }
+        })
+        .transpose()?;
+    let mut blob = None;
+    if repo.is_bare() {
+        // nothing is written on disk, the commit is made from the blob
+        if remote_changed {
+            let oid = match (&final_ro_content, remote_entry) {
+                (Some(content), _) => repo.blob(content).map_err(|e| e.message().to_owned())?,
+                (None, Some((oid, _))) => oid,
+                (None, None) => return Err(format!("{} is not in the repository", repo_file_path)),
+            };
+            let mode = match local_executable {
+                Some(true) => git2::FileMode::BlobExecutable,
+                _ => git2::FileMode::Blob,
+            };
+            blob = Some((oid, mode));
+        }
+    } else {
+        let repo_file = git_folder.to_owned() + "/" + &repo_file_path;
+        let repo_mode = local_executable.map(|executable| if executable { 0o755 } else { 0o644 });
+        if let (true, None, Some(mode)) = (mode_changed, &final_ro_content, repo_mode) {
+            set_file_mode(&repo_file, mode)?;
+        }
+        if let Some(content) = final_ro_content {
+            write_to_file(&repo_file, &content, repo_mode)?;
+        }
+    }
+    Ok(LinkReport {
+        local_path: file_path.clone(),
+        source_path: repo_file_path,
+        lines_added_locally: merge.lines_added_locally,
+        lines_added_remotely: merge.lines_added_remotely,
+        remote_changed,
+        blob,
+        ..LinkReport::default()
+    })
+}
+
+#[cfg(test)]
+pub mod tests {
+    use pretty_assertions::assert_eq;
+    use std::fs;
+    use std::os::unix::fs::PermissionsExt;
+
+    use git2::{FileMode, Oid, Repository, Signature};
+
+    use crate::config::GitLink;
+    use crate::core::{init, process_file};
+    use crate::file::file_mode;
+    use crate::file::parse_config;
+    use crate::git::tests::Fixture;
+
+    #[test]
+    fn test_process_file_mode() {
+        let folder = tempfile::tempdir().unwrap();
+        let repo_folder = folder.path().join("repo");
+        let repo = Repository::init(&repo_folder).unwrap();
+        let sign = Signature::now("Someone", "someone@example.com").unwrap();
+        let blob = repo.blob(b"a\n").unwrap();
+        let commit = |mode: FileMode| -> Oid {
+            let mut builder = repo.treebuilder(None).unwrap();
+            builder.insert("script", blob, mode.into()).unwrap();
+            let tree = repo.find_tree(builder.write().unwrap()).unwrap();
+            repo.commit(None, &sign, &sign, "sync", &tree, &[]).unwrap()
+        };
+        let executable = commit(FileMode::BlobExecutable);
+        let not_executable = commit(FileMode::Blob);
+        repo.reference("refs/remotes/http-origin/master", executable, true, "fetch")
+            .unwrap();
+        repo.set_head_detached(executable).unwrap();
+        repo.checkout_head(Some(git2::build::CheckoutBuilder::new().force()))
+            .unwrap();
+
+        let link: GitLink =
+            serde_json::from_value(serde_json::json!({ "source_path": "script" })).unwrap();
+        let local = folder.path().join("script").to_string_lossy().into_owned();
+        let git_folder = repo_folder.to_string_lossy().into_owned();
+        let repo_file = repo_folder.join("script").to_string_lossy().into_owned();
+        let run = |synced: Oid| {
+            fs::write(&local, "a\n").unwrap();
+            fs::set_permissions(&local, fs::Permissions::from_mode(0o600)).unwrap();
+            process_file(
+                &link,
+                &local,
+                "script".to_owned(),
+                &git_folder,
+                &repo,
+                Some(synced),
+            )
+            .unwrap()
+            .remote_changed
+        };
+
+        // made executable on the remote since the last sync
+        assert!(!run(not_executable));
+        assert_eq!(Some(0o700), file_mode(&local));
+        assert_eq!(Some(0o755), file_mode(&repo_file));
+
+        // made not executable locally since the last sync
+        assert!(run(executable));
+        assert_eq!(Some(0o600), file_mode(&local));
+        assert_eq!(Some(0o644), file_mode(&repo_file));
     }
-    if let Some(content_to_encrypt) = remote_result {
-        let final_ro_content = if let Some(password_file) = file_appender.clone().password_file {
-            let passphrase = get_file_contents(&password_file).unwrap();
-            encrypt(
-                &content_to_encrypt,
-                String::from_utf8(passphrase).unwrap().into_boxed_str(),
+
+    #[test]
+    fn test_init() {
+        let fixture = Fixture::new();
+        let path = |name: &str| fixture.path(name);
+        let config_path = path("config.json");
+        let init = |location: &str| {
+            init(
+                config_path.clone(),
+                fixture.url.clone(),
+                path(location),
+                None,
+                None,
+                None,
             )
-        } else {
-            content_to_encrypt
         };
-        write_to_file(
-            &(git_folder.to_owned() + "/" + &repo_file_path),
-            &final_ro_content,
-        );
-        files.push(repo_file_path.clone());
+
+        assert_eq!(Ok(()), init("repo"));
+        assert!(fixture.join("repo/.git").exists());
+        let config = parse_config(config_path.clone());
+        assert!(config.appenders.contains_key(&path("repo")));
+        // nothing is cloned for an appender already in the config
+        fs::remove_dir_all(path("repo")).unwrap();
+        assert!(init("repo").is_err());
+        assert!(!fixture.join("repo").exists());
     }
-    files
 }
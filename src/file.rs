@@ -1,31 +1,196 @@
 use crate::config;
+use crate::log;
+use std::env;
 use std::fs::File;
-use std::path::Path;
+use std::os::unix::fs::{fchown, MetadataExt, PermissionsExt};
+use std::path::{Path, PathBuf};
+use std::process;
+use std::time::SystemTime;
 use std::{
     fs::{self},
-    io::{self, BufRead, BufReader, Write},
+    io::{self, Write},
 };
 
-pub fn write_to_file(path: &String, content: &Vec<u8>) {
-    println!("writing to {}", path);
-    fs::create_dir_all(Path::new(path).parent().unwrap()).unwrap();
-    let mut file = File::create(path).expect(&format!("Could not find {}", path));
-    file.write_all(&content).unwrap();
+/// Line terminator used by a file, so it can be written back the way it was read.
+#[derive(Debug, Clone, Copy, Default, Eq, PartialEq)]
+pub enum LineEnding {
+    #[default]
+    Lf,
+    CrLf,
 }
 
-pub fn get_file_contents_as_lines(path: &String) -> io::Result<Vec<Vec<u8>>> {
-    let file = File::open(path)?;
+impl LineEnding {
+    /// Turns `\n` separated content into content using this line ending.
+    pub fn apply(&self, content: Vec<u8>) -> Vec<u8> {
+        match self {
+            LineEnding::Lf => content,
+            LineEnding::CrLf => content.into_iter().fold(Vec::new(), |mut acc, c| {
+                if c == b'\n' {
+                    acc.push(b'\r');
+                }
+                acc.push(c);
+                acc
+            }),
+        }
+    }
+}
+
+/// What identifies a version of a file, to notice it changed since it was read.
+#[derive(Debug, Clone, Eq, PartialEq)]
+pub struct FileStamp {
+    len: u64,
+    modified: Option<SystemTime>,
+    inode: u64,
+}
+
+/// The stamp of the file, `None` if it does not exist.
+pub fn file_stamp(path: &String) -> Option<FileStamp> {
+    fs::metadata(path).ok().map(|metadata| FileStamp {
+        len: metadata.len(),
+        modified: metadata.modified().ok(),
+        inode: metadata.ino(),
+    })
+}
+
+/// Writes the file with `mode` if given, with the mode of the replaced file otherwise.
+pub fn write_to_file(path: &String, content: &[u8], mode: Option<u32>) -> Result<(), String> {
+    replace_file(path, content, mode, |_| true).map(|_| ())
+}
+
+/// Writes the file only if it is still as it was when `stamp` was taken.
+/// Returns false, without writing anything, if it was modified in the meantime.
+pub fn write_to_file_if_unchanged(
+    path: &String,
+    content: &[u8],
+    mode: Option<u32>,
+    stamp: &Option<FileStamp>,
+) -> Result<bool, String> {
+    replace_file(path, content, mode, |target| {
+        &file_stamp(&target.to_string_lossy().into_owned()) == stamp
+    })
+}
+
+/// Writes to a temporary file next to the target and renames it over the target,
+/// so the file is never seen half written. The permissions and owner of the replaced file are kept.
+/// Symbolic links are followed, the file they point to is replaced.
+fn replace_file(
+    path: &String,
+    content: &[u8],
+    mode: Option<u32>,
+    unchanged: impl FnOnce(&Path) -> bool,
+) -> Result<bool, String> {
+    log!("writing to {}", path);
+    let target = fs::canonicalize(path).unwrap_or(PathBuf::from(path));
+    let (Some(folder), Some(name)) = (target.parent(), target.file_name()) else {
+        return Err(format!("{} is not a file path", path));
+    };
+    fs::create_dir_all(folder)
+        .map_err(|e| format!("Could not create {}: {}", folder.display(), e))?;
+    let temp = folder.join(format!(
+        ".{}.git-append-{}.tmp",
+        name.to_string_lossy(),
+        process::id()
+    ));
+    let written = write_temp_file(&temp, &target, content, mode);
+    if let Err(e) = written {
+        let _ = fs::remove_file(&temp);
+        return Err(format!("Could not write {}: {}", temp.display(), e));
+    }
+    if !unchanged(&target) {
+        let _ = fs::remove_file(&temp);
+        return Ok(false);
+    }
+    fs::rename(&temp, &target)
+        .map_err(|e| format!("Could not replace {}: {}", target.display(), e))?;
+    Ok(true)
+}
+
+fn write_temp_file(
+    temp: &Path,
+    target: &Path,
+    content: &[u8],
+    mode: Option<u32>,
+) -> io::Result<()> {
+    let mut file = File::create(temp)?;
+    file.write_all(content)?;
+    if let Ok(metadata) = fs::metadata(target) {
+        file.set_permissions(metadata.permissions())?;
+        let created = file.metadata()?;
+        if (created.uid(), created.gid()) != (metadata.uid(), metadata.gid()) {
+            if let Err(e) = fchown(&file, Some(metadata.uid()), Some(metadata.gid())) {
+                log!("Could not keep the owner of {}: {}", target.display(), e);
+            }
+        }
+    }
+    if let Some(mode) = mode {
+        file.set_permissions(fs::Permissions::from_mode(mode))?;
+    }
+    file.sync_all()
+}
+
+/// Parses an octal mode, such as `0600`.
+pub fn parse_mode(mode: &str) -> Result<u32, String> {
+    u32::from_str_radix(mode, 8)
+        .ok()
+        .filter(|mode| mode <= &0o7777)
+        .ok_or(format!(
+            "Invalid mode {}, expected an octal mode such as 0600",
+            mode
+        ))
+}
+
+/// The permission bits of the file, `None` if it does not exist.
+pub fn file_mode(path: &String) -> Option<u32> {
+    fs::metadata(path)
+        .ok()
+        .map(|metadata| metadata.permissions().mode() & 0o7777)
+}
+
+/// Changes the permission bits of an existing file, if they differ.
+pub fn set_file_mode(path: &String, mode: u32) -> Result<(), String> {
+    if file_mode(path).is_some_and(|current| current != mode) {
+        log!("Setting mode {:o} on {}", mode, path);
+        fs::set_permissions(path, fs::Permissions::from_mode(mode))
+            .map_err(|e| format!("Could not set the mode of {}: {}", path, e))?;
+    }
+    Ok(())
+}
 
-    Ok(io::BufReader::new(file)
-        .lines()
+/// Splits on `\n` only, keeping every other byte as is.
+/// A file is considered `CrLf` only when all of its line breaks are `\r\n`,
+/// in which case the `\r` is stripped from each line, and from a final line cut before its `\n`.
+pub fn split_lines(content: &[u8]) -> (Vec<Vec<u8>>, LineEnding) {
+    let mut lines: Vec<&[u8]> = content.split(|c| c == &b'\n').collect();
+    // the last chunk is the (possibly empty) remainder after the last `\n`
+    let remainder = lines.pop().unwrap_or_default();
+    let line_ending = if !lines.is_empty() && lines.iter().all(|l| l.ends_with(b"\r")) {
+        LineEnding::CrLf
+    } else {
+        LineEnding::Lf
+    };
+    let mut lines: Vec<Vec<u8>> = lines
         .into_iter()
-        .flat_map(|l| l.ok())
-        .map(|l| l.as_bytes().into())
-        .collect())
+        .map(|l| match line_ending {
+            LineEnding::CrLf => l[..l.len() - 1].to_vec(),
+            LineEnding::Lf => l.to_vec(),
+        })
+        .collect();
+    let remainder = match line_ending {
+        LineEnding::CrLf => remainder.strip_suffix(b"\r").unwrap_or(remainder),
+        LineEnding::Lf => remainder,
+    };
+    if !remainder.is_empty() {
+        lines.push(remainder.to_vec());
+    }
+    (lines, line_ending)
+}
+
+pub fn get_file_contents_as_lines(path: &String) -> io::Result<(Vec<Vec<u8>>, LineEnding)> {
+    fs::read(path).map(|content| split_lines(&content))
 }
 
 pub fn get_file_contents(path: &String) -> Result<Vec<u8>, std::io::Error> {
-    println!("Get file content: {}", path);
+    log!("Get file content: {}", path);
     fs::read(path)
 }
 
@@ -40,21 +205,141 @@ pub fn get_file_contents_strip_final_end_line(path: &String) -> Result<Vec<u8>,
     })
 }
 
+/// Reads the config with its includes, merges the overlay of the current host and the defaults.
 pub fn parse_config(path: String) -> config::Config {
-    let file = File::open(path.clone()).expect("Cannot open path.");
-    let reader = BufReader::new(file);
-    let expected: config::Config =
-        serde_json::from_reader(reader).expect(&format!("Invalid format {}", path));
-    expected
+    read_config_file(&path).unwrap_or_else(|e| panic!("{}", e))
+}
+
+/// Same as `parse_config`, an invalid config being an error.
+pub fn read_config_file(path: &str) -> Result<config::Config, String> {
+    load_config(Path::new(path), &mut Vec::new(), &mut Vec::new())
+        .map(|config| config.apply_host(&config::hostname()))
+        .and_then(|config| config.resolve_defaults())
+        .map_err(|e| format!("Invalid config {}: {}", path, e))
+}
+
+/// Reads a config file and the files it includes, the included files are merged first.
+/// Every file read is pushed to `loaded`.
+pub fn load_config(
+    path: &Path,
+    including: &mut Vec<PathBuf>,
+    loaded: &mut Vec<PathBuf>,
+) -> Result<config::Config, String> {
+    let canonical =
+        fs::canonicalize(path).map_err(|e| format!("Cannot open {}: {}", path.display(), e))?;
+    if including.contains(&canonical) {
+        return Err(format!("Include cycle on {}", path.display()));
+    }
+    including.push(canonical);
+    loaded.push(path.to_path_buf());
+
+    let config = read_config(path)?.expand_paths()?;
+    let folder = path.parent().unwrap_or(Path::new(""));
+    let mut merged = config::Config::default();
+    for include in config.include.iter() {
+        merged = merged.merge(load_config(&folder.join(include), including, loaded)?);
+    }
+
+    including.pop();
+    Ok(merged.merge(config))
+}
+
+/// Reads a single config file, the format is chosen from the extension (`.toml`, `.yaml`/`.yml`, JSON otherwise).
+/// Parse errors are reported as `path:line:column: message`.
+fn read_config(path: &Path) -> Result<config::Config, String> {
+    let content =
+        fs::read_to_string(path).map_err(|e| format!("Cannot open {}: {}", path.display(), e))?;
+    let invalid_format = |(line, column): (usize, usize), e: String| {
+        format!("{}:{}:{}: {}", path.display(), line, column, e)
+    };
+    match path.extension().and_then(|e| e.to_str()) {
+        Some("toml") => toml::from_str(&content).map_err(|e| {
+            let start = e.span().map(|s| s.start).unwrap_or_default();
+            invalid_format(line_column(&content, start), e.message().to_owned())
+        }),
+        Some("yaml") | Some("yml") => serde_yaml::from_str(&content).map_err(|e| {
+            let location = e
+                .location()
+                .map(|l| (l.line(), l.column()))
+                .unwrap_or((1, 1));
+            invalid_format(location, e.to_string())
+        }),
+        _ => serde_json::from_str(&content)
+            .map_err(|e| invalid_format((e.line(), e.column()), e.to_string())),
+    }
+}
+
+/// 1-based line and column of a byte offset.
+pub fn line_column(content: &str, offset: usize) -> (usize, usize) {
+    let before = &content[..offset.min(content.len())];
+    let line = before.matches('\n').count() + 1;
+    let column = before.len() - before.rfind('\n').map(|i| i + 1).unwrap_or(0) + 1;
+    (line, column)
+}
+
+/// Expands a leading `~` and any `$VAR` or `${VAR}` from the environment.
+pub fn expand_path(path: &str) -> Result<String, String> {
+    let path = match path.strip_prefix('~') {
+        Some(rest) if rest.is_empty() || rest.starts_with('/') => {
+            let home = env::var("HOME").map_err(|_| format!("HOME is not set for {}", path))?;
+            home + rest
+        }
+        _ => path.to_owned(),
+    };
+
+    let mut expanded = String::new();
+    let mut chars = path.chars().peekable();
+    while let Some(c) = chars.next() {
+        if c != '$' {
+            expanded.push(c);
+            continue;
+        }
+        let name: String = if chars.peek() == Some(&'{') {
+            chars.next();
+            let mut name = String::new();
+            loop {
+                match chars.next() {
+                    Some('}') => break,
+                    Some(c) => name.push(c),
+                    None => return Err(format!("Unterminated ${{ in {}", path)),
+                }
+            }
+            if name.is_empty() {
+                return Err(format!("Empty variable in {}", path));
+            }
+            name
+        } else {
+            let mut name = String::new();
+            while let Some(c) = chars.next_if(|c| c.is_ascii_alphanumeric() || c == &'_') {
+                name.push(c);
+            }
+            name
+        };
+        if name.is_empty() {
+            expanded.push('$');
+        } else {
+            expanded.push_str(
+                &env::var(&name).map_err(|_| format!("{} is not set for {}", name, path))?,
+            );
+        }
+    }
+    Ok(expanded)
 }
 
 #[cfg(test)]
 pub mod tests {
     use crate::{
-        config::{self, Feature, GitAppender, GitConfig, GitLink},
+        config::{self, Feature, GitAppender, GitConfig, GitLink, Mirror, Network},
+        file::{
+            expand_path, file_mode, file_stamp, load_config, parse_mode, split_lines,
+            write_to_file, write_to_file_if_unchanged, LineEnding,
+        },
         parse_config,
     };
     use pretty_assertions::assert_eq;
+    use std::fs;
+    use std::os::unix::fs::PermissionsExt;
+    use std::path::Path;
 
     #[test]
     fn test_example_config() {
@@ -68,6 +353,10 @@ pub mod tests {
                                 username: "someone".to_owned(),
                                 token_file: "/passwords/github_token".to_owned()
                             }),
+                            bare: None,
+                            url: None,
+                            certificate_sha256: None,
+                            mirrors: std::collections::HashMap::new(),
                             links: vec![
                                 (
                                     "/home/local/plaintext_file".to_string(),
@@ -82,6 +371,9 @@ pub mod tests {
                                         ),
                                         exclude_patterns: None,
                                         features: None,
+                                        mode: None,
+                                        template: None,
+                                        unset: None,
                                     }
                                 ),
                                 (
@@ -99,18 +391,31 @@ pub mod tests {
                                                 .into_iter()
                                                 .collect()
                                         ),
+                                        mode: None,
+                                        template: None,
+                                        unset: None,
                                     }
                                 )
                             ]
                             .into_iter()
                             .collect(),
-                            folder_links: std::collections::HashMap::new()
+                            folder_links: std::collections::HashMap::new(),
+                            defaults: None,
+                            allow_staged_changes: None,
+                            commit_message: None,
+                            author: None,
+                            signing: None,
+                            pull_policy: None,
                         }
                     ),
                     (
                         "/home/some/other/repository-location".to_string(),
                         GitAppender {
                             git_config: None,
+                            bare: None,
+                            url: None,
+                            certificate_sha256: None,
+                            mirrors: std::collections::HashMap::new(),
                             links: vec![(
                                 "/plaintext_file".to_string(),
                                 GitLink {
@@ -120,6 +425,9 @@ pub mod tests {
                                     remove_lines: None,
                                     exclude_patterns: None,
                                     features: None,
+                                    mode: None,
+                                    template: None,
+                                    unset: None,
                                 }
                             ),]
                             .into_iter()
@@ -133,15 +441,29 @@ pub mod tests {
                                     remove_lines: None,
                                     exclude_patterns: None,
                                     features: None,
+                                    mode: None,
+                                    template: None,
+                                    unset: None,
                                 }
                             ),]
                             .into_iter()
                             .collect(),
+                            defaults: None,
+                            allow_staged_changes: None,
+                            commit_message: None,
+                            author: None,
+                            signing: None,
+                            pull_policy: None,
                         }
                     )
                 ]
                 .into_iter()
-                .collect()
+                .collect(),
+                defaults: None,
+                templates: std::collections::HashMap::new(),
+                include: Vec::new(),
+                hosts: std::collections::HashMap::new(),
+                network: None,
             },
             parse_config(String::from("tests/example-config.json"))
         );
@@ -158,6 +480,10 @@ pub mod tests {
                             username: "<github-user>".to_owned(),
                             token_file: "/passwords/github_token".to_owned()
                         }),
+                        bare: None,
+                        url: None,
+                        certificate_sha256: None,
+                        mirrors: std::collections::HashMap::new(),
                         links: std::collections::HashMap::new(),
                         folder_links: vec![(
                             "/home/<user>/.directory_history".to_string(),
@@ -168,18 +494,377 @@ pub mod tests {
                                 remove_lines: None,
                                 exclude_patterns: None,
                                 features: None,
+                                mode: None,
+                                template: None,
+                                unset: None,
                             }
                         ),]
                         .into_iter()
                         .collect(),
+                        defaults: None,
+                        allow_staged_changes: None,
+                        commit_message: None,
+                        author: None,
+                        signing: None,
+                        pull_policy: None,
                     }
                 ),]
                 .into_iter()
-                .collect()
+                .collect(),
+                defaults: None,
+                templates: std::collections::HashMap::new(),
+                include: Vec::new(),
+                hosts: std::collections::HashMap::new(),
+                network: None,
             },
             parse_config(String::from(
                 "tests/example-per-directory-history-config.json"
             ))
         );
     }
+
+    #[test]
+    fn test_defaults_config() {
+        let link = |source_path: &str| GitLink {
+            source_path: source_path.to_string(),
+            password_file: Some(String::from("/home/password-file")),
+            source_branch: Some(String::from("main")),
+            remove_lines: Some(
+                vec![String::from("first_ignored_line")]
+                    .into_iter()
+                    .collect(),
+            ),
+            exclude_patterns: None,
+            features: None,
+            mode: None,
+            template: None,
+            unset: None,
+        };
+        assert_eq!(
+            config::Config {
+                appenders: vec![(
+                    "/home/someone/repository-location".to_string(),
+                    GitAppender {
+                        git_config: None,
+                        bare: None,
+                        url: None,
+                        certificate_sha256: None,
+                        mirrors: std::collections::HashMap::new(),
+                        links: vec![
+                            (
+                                "/home/someone/.zsh_history".to_string(),
+                                GitLink {
+                                    exclude_patterns: Some(
+                                        vec![String::from(".*\\\\$")].into_iter().collect()
+                                    ),
+                                    features: Some(
+                                        vec![Feature::RemoveMultilinesBash].into_iter().collect()
+                                    ),
+                                    ..link(".zsh_history")
+                                }
+                            ),
+                            (
+                                "/home/someone/journal".to_string(),
+                                GitLink {
+                                    source_branch: Some(String::from("journal")),
+                                    remove_lines: None,
+                                    ..link("journal")
+                                }
+                            ),
+                            (
+                                "/home/someone/public".to_string(),
+                                GitLink {
+                                    password_file: None,
+                                    ..link("public")
+                                }
+                            ),
+                        ]
+                        .into_iter()
+                        .collect(),
+                        folder_links: std::collections::HashMap::new(),
+                        defaults: None,
+                        allow_staged_changes: None,
+                        commit_message: None,
+                        author: None,
+                        signing: None,
+                        pull_policy: None,
+                    }
+                ),]
+                .into_iter()
+                .collect(),
+                defaults: None,
+                templates: std::collections::HashMap::new(),
+                include: Vec::new(),
+                hosts: std::collections::HashMap::new(),
+                network: None,
+            },
+            parse_config(String::from("tests/example-defaults-config.json"))
+        );
+    }
+
+    #[test]
+    fn test_include_and_hosts_config() {
+        let config = load_config(
+            Path::new("tests/example-include-config.yaml"),
+            &mut Vec::new(),
+            &mut Vec::new(),
+        )
+        .unwrap()
+        .apply_host("laptop")
+        .resolve_defaults()
+        .unwrap();
+        let mut expected = parse_config(String::from(
+            "tests/example-per-directory-history-config.json",
+        ));
+        expected
+            .appenders
+            .get_mut("/home/<user>/repository-location")
+            .unwrap()
+            .links
+            .insert(
+                "/home/<user>/.zsh_history".to_string(),
+                GitLink {
+                    source_path: ".zsh_history".to_string(),
+                    password_file: None,
+                    source_branch: None,
+                    remove_lines: None,
+                    exclude_patterns: None,
+                    features: None,
+                    mode: None,
+                    template: None,
+                    unset: None,
+                },
+            );
+        assert_eq!(expected, config);
+    }
+
+    #[test]
+    fn test_bare_config() {
+        let config = load_config(
+            Path::new("tests/example-bare-config.yaml"),
+            &mut Vec::new(),
+            &mut Vec::new(),
+        )
+        .unwrap()
+        .apply_host("laptop")
+        .resolve_defaults()
+        .unwrap();
+        let git_folder = format!("{}/git-append/history", config::data_home().unwrap());
+        assert_eq!(
+            vec![&git_folder],
+            config.appenders.keys().collect::<Vec<_>>()
+        );
+        let mut links: Vec<&String> = config.appenders[&git_folder].links.keys().collect();
+        links.sort();
+        assert_eq!(
+            vec!["/home/<user>/.bash_history", "/home/<user>/.zsh_history"],
+            links
+        );
+        // as given on the command line
+        assert_eq!(&git_folder, config.find_appender("history").unwrap().0);
+        assert!(config.find_appender("other").is_err());
+    }
+
+    #[test]
+    fn test_mirrors_config() {
+        let home = std::env::var("HOME").unwrap();
+        let config = load_config(
+            Path::new("tests/example-mirrors-config.yaml"),
+            &mut Vec::new(),
+            &mut Vec::new(),
+        )
+        .unwrap()
+        .apply_host("laptop")
+        .resolve_defaults()
+        .unwrap();
+        let mirrors = &config.appenders[&format!("{}/repository-location", home)].mirrors;
+        assert_eq!(
+            std::collections::HashMap::from([
+                (
+                    "github".to_owned(),
+                    Mirror {
+                        url: "https://github.com/someone/history.git".to_owned(),
+                        git_config: Some(GitConfig {
+                            username: "someone".to_owned(),
+                            token_file: format!("{}/.github-token", home),
+                        }),
+                        certificate_sha256: None,
+                    }
+                ),
+                (
+                    "backup".to_owned(),
+                    Mirror {
+                        url: "https://backup.example.com/history.git".to_owned(),
+                        git_config: None,
+                        certificate_sha256: Some("AB:CD".to_owned()),
+                    }
+                ),
+            ]),
+            *mirrors
+        );
+    }
+
+    #[test]
+    fn test_network_config() {
+        let load = |hostname: &str| {
+            load_config(
+                Path::new("tests/example-network-config.yaml"),
+                &mut Vec::new(),
+                &mut Vec::new(),
+            )
+            .unwrap()
+            .apply_host(hostname)
+            .resolve_defaults()
+            .unwrap()
+            .network
+        };
+        let network = Network {
+            connect_timeout: Some(10),
+            timeout: None,
+            retries: Some(5),
+            proxy: Some("http://proxy.example.com:3128".to_owned()),
+            ca_file: Some(format!("{}/ca.pem", std::env::var("HOME").unwrap())),
+        };
+        assert_eq!(Some(network.clone()), load("desktop"));
+        // each value of the host wins on its own
+        assert_eq!(
+            Some(Network {
+                retries: Some(0),
+                proxy: Some("http://laptop-proxy.example.com:3128".to_owned()),
+                ..network
+            }),
+            load("laptop")
+        );
+    }
+
+    #[test]
+    fn test_toml_and_yaml_config() {
+        let expected = parse_config(String::from("tests/example-config.json"));
+        assert_eq!(
+            expected,
+            parse_config(String::from("tests/example-config.toml"))
+        );
+        assert_eq!(
+            expected,
+            parse_config(String::from("tests/example-config.yaml"))
+        );
+    }
+
+    #[test]
+    fn test_expand_path() {
+        let home = std::env::var("HOME").unwrap();
+        assert_eq!(
+            Ok(format!("{}/.zsh_history", home)),
+            expand_path("~/.zsh_history")
+        );
+        assert_eq!(
+            Ok(format!("{}/.zsh_history", home)),
+            expand_path("$HOME/.zsh_history")
+        );
+        assert_eq!(Ok(format!("/a/{}/b", home)), expand_path("/a/${HOME}/b"));
+        assert_eq!(Ok(String::from("/a/~/b$")), expand_path("/a/~/b$"));
+        assert!(expand_path("${GIT_APPEND_SURELY_UNSET}/file").is_err());
+        assert_eq!(
+            Err(String::from("Unterminated ${ in /a/${HOME/b")),
+            expand_path("/a/${HOME/b")
+        );
+    }
+
+    #[test]
+    fn test_config_schema() {
+        let schema = config::schema();
+        let committed: serde_json::Value =
+            serde_json::from_str(&std::fs::read_to_string("tests/config-schema.json").unwrap())
+                .unwrap();
+        assert_eq!(committed, schema);
+
+        let validator = jsonschema::validator_for(&schema).unwrap();
+        for path in [
+            "tests/example-config.json",
+            "tests/example-per-directory-history-config.json",
+            "tests/example-defaults-config.json",
+        ] {
+            let example: serde_json::Value =
+                serde_json::from_str(&std::fs::read_to_string(path).unwrap()).unwrap();
+            assert!(validator.is_valid(&example), "{} is not valid", path);
+        }
+        let example: serde_json::Value = serde_yaml::from_str(
+            &std::fs::read_to_string("tests/example-include-config.yaml").unwrap(),
+        )
+        .unwrap();
+        assert!(validator.is_valid(&example));
+        assert!(!validator.is_valid(&serde_json::json!({"appenders": {"/repo": {"unknown": 1}}})));
+    }
+
+    #[test]
+    fn test_split_lines() {
+        assert_eq!(
+            (vec![b"a".to_vec(), b"b".to_vec()], LineEnding::Lf),
+            split_lines(b"a\nb\n")
+        );
+        assert_eq!(
+            (vec![b"a".to_vec(), b"b".to_vec()], LineEnding::CrLf),
+            split_lines(b"a\r\nb\r\n")
+        );
+        assert_eq!(
+            (vec![b"a\r".to_vec(), b"b".to_vec()], LineEnding::Lf),
+            split_lines(b"a\r\nb\n")
+        );
+        assert_eq!(
+            (vec![vec![0xff], b"b".to_vec()], LineEnding::CrLf),
+            split_lines(b"\xff\r\nb\r")
+        );
+        assert_eq!(
+            (vec![b"a".to_vec()], LineEnding::CrLf),
+            split_lines(b"a\r\n\r")
+        );
+        assert_eq!((vec![], LineEnding::Lf), split_lines(b""));
+    }
+
+    #[test]
+    fn test_line_ending_apply() {
+        assert_eq!(b"a\nb\n".to_vec(), LineEnding::Lf.apply(b"a\nb\n".to_vec()));
+        assert_eq!(
+            b"a\r\nb\r\n".to_vec(),
+            LineEnding::CrLf.apply(b"a\nb\n".to_vec())
+        );
+    }
+
+    #[test]
+    fn test_write_to_file_if_unchanged() {
+        let path = std::env::temp_dir()
+            .join(format!("git-append-test-{}", std::process::id()))
+            .to_string_lossy()
+            .into_owned();
+        fs::write(&path, "a\n").unwrap();
+        fs::set_permissions(&path, fs::Permissions::from_mode(0o600)).unwrap();
+
+        let stamp = file_stamp(&path);
+        fs::write(&path, "a\nb\n").unwrap();
+        assert_eq!(
+            Ok(false),
+            write_to_file_if_unchanged(&path, b"a\nc\n", None, &stamp)
+        );
+        assert_eq!(b"a\nb\n".to_vec(), fs::read(&path).unwrap());
+
+        let stamp = file_stamp(&path);
+        assert_eq!(
+            Ok(true),
+            write_to_file_if_unchanged(&path, b"a\nb\nc\n", None, &stamp)
+        );
+        assert_eq!(b"a\nb\nc\n".to_vec(), fs::read(&path).unwrap());
+        assert_eq!(Some(0o600), file_mode(&path));
+
+        write_to_file(&path, b"a\n", Some(0o640)).unwrap();
+        assert_eq!(Some(0o640), file_mode(&path));
+        fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn test_parse_mode() {
+        assert_eq!(Ok(0o600), parse_mode("0600"));
+        assert_eq!(Ok(0o755), parse_mode("755"));
+        assert!(parse_mode("0800").is_err());
+        assert!(parse_mode("17777").is_err());
+    }
 }
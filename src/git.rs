@@ -1,167 +1,903 @@
 use std::path::Path;
+use std::thread;
+use std::time::Duration;
 
 use git2::{
-    Cred, DiffFormat, Direction, FetchOptions, Index, IndexAddOption, Oid, PushOptions,
-    RemoteCallbacks, Repository, Signature,
+    build::{RepoBuilder, TreeUpdateBuilder},
+    CertificateCheckStatus, Cred, Delta, Direction, ErrorCode, FetchOptions, FileMode, Index, Oid,
+    ProxyOptions, PushOptions, Remote, RemoteCallbacks, Repository, Signature, Tree,
 };
 
-pub fn open(path: &String) -> Repository {
-    Repository::open(path).unwrap()
+use sha2::{Digest, Sha256};
+
+use crate::config::{hostname, Author, PullPolicy};
+use crate::log;
+use crate::message::is_git_append_message;
+use crate::signing::Signer;
+
+/// How to reach a remote.
+#[derive(Debug, Clone, Default)]
+pub struct RemoteOptions {
+    pub credentials: Option<(String, String)>,
+    /// Proxy URL, the proxy is found like git does when missing (`http.proxy`, `HTTPS_PROXY`...).
+    pub proxy: Option<String>,
+    /// Attempts after failing to reach the remote.
+    pub retries: u32,
+    /// SHA-256 fingerprint of the only certificate accepted for the remote.
+    pub certificate_sha256: Option<String>,
+}
+
+/// Sets the timeouts of every connection, libgit2 only has process-wide ones.
+pub fn set_timeouts(connect_timeout: Duration, timeout: Duration) {
+    let millis = |duration: Duration| i32::try_from(duration.as_millis()).unwrap_or(i32::MAX);
+    // only set before any connection is made
+    unsafe {
+        git2::opts::set_server_connect_timeout_in_milliseconds(millis(connect_timeout)).unwrap();
+        git2::opts::set_server_timeout_in_milliseconds(millis(timeout)).unwrap();
+    }
 }
 
-pub fn commit_and_push(repo: &Repository, credentials: Option<(String, String)>, sign: &Signature) {
-    if let Some(_oid) = commit(repo, sign) {
-        push(repo, credentials);
+/// Trusts the CA certificates of `ca_file` in addition to the system ones, for every remote.
+pub fn add_ca_file(ca_file: &str) -> Result<(), String> {
+    if !Path::new(ca_file).is_file() {
+        return Err(format!("CA file {} not found", ca_file));
+    }
+    // only called before any connection is made
+    unsafe { git2::opts::set_ssl_cert_file(ca_file) }
+        .map_err(|e| format!("Could not load CA file {}: {}", ca_file, e.message()))
+}
+
+/// Parses a SHA-256 fingerprint, in hexadecimal with or without `:` separators.
+pub fn parse_sha256(fingerprint: &str) -> Result<Vec<u8>, String> {
+    let hex: String = fingerprint.chars().filter(|c| *c != ':').collect();
+    let invalid = || {
+        format!(
+            "Invalid SHA-256 fingerprint {}, expected 64 hexadecimal digits",
+            fingerprint
+        )
+    };
+    if hex.len() != 64 || !hex.is_ascii() {
+        return Err(invalid());
+    }
+    (0..64)
+        .step_by(2)
+        .map(|i| u8::from_str_radix(&hex[i..i + 2], 16).map_err(|_| invalid()))
+        .collect()
+}
+
+fn format_sha256(digest: &[u8]) -> String {
+    digest
+        .iter()
+        .map(|b| format!("{:02X}", b))
+        .collect::<Vec<_>>()
+        .join(":")
+}
+
+/// A readable message for the errors of a fetch or a push.
+pub fn describe_error(e: &git2::Error) -> String {
+    if e.code() == ErrorCode::Certificate && e.class() != git2::ErrorClass::Callback {
+        format!(
+            "The certificate of the remote could not be verified ({}), add its CA with `network.ca_file` or pin it with `certificate_sha256`",
+            e.message()
+        )
+    } else {
+        e.message().to_owned()
     }
 }
 
-fn commit(repo: &Repository, sign: &Signature) -> Option<Oid> {
+/// The message of a git error, for the errors returned as `String`.
+fn error_message(e: git2::Error) -> String {
+    e.message().to_owned()
+}
+
+pub fn open(path: &String) -> Result<Repository, String> {
+    Repository::open(path).map_err(|e| format!("Could not open {}: {}", path, e.message()))
+}
+
+/// Opens a bare repository, creating it with `url` as `http-origin` if it does not exist yet.
+pub fn open_or_init_bare(path: &String, url: Option<&str>) -> Result<Repository, String> {
+    if let Ok(repo) = Repository::open_bare(path) {
+        return Ok(repo);
+    }
+    let url = url.ok_or_else(|| format!("{} is not a repository, set `url` to create it", path))?;
+    log!("Creating bare repository {} for {}", path, url);
+    let repo = Repository::init_bare(path)
+        .map_err(|e| format!("Could not create {}: {}", path, e.message()))?;
+    repo.remote("http-origin", url).map_err(error_message)?;
+    Ok(repo)
+}
+
+/// Clones `url` with `http-origin` as remote name.
+/// If the remote is empty, a first empty commit is pushed on `master`.
+pub fn clone(url: &str, path: &Path, options: &RemoteOptions) -> Result<Repository, git2::Error> {
+    let repo = with_retries(options.retries, || {
+        let mut fetch_options = FetchOptions::new();
+        fetch_options.remote_callbacks(create_callbacks(options));
+        fetch_options.proxy_options(proxy_options(options));
+        RepoBuilder::new()
+            .fetch_options(fetch_options)
+            .remote_create(|repo, _, url| repo.remote("http-origin", url))
+            .clone(url, path)
+    })?;
+    if repo.head().is_err() {
+        initial_commit(&repo)?;
+        let mut remote = repo.find_remote("http-origin")?;
+        push_remote(&mut remote, options)?;
+    }
+    Ok(repo)
+}
+
+/// An empty first commit on `master`, for a repository without any.
+pub fn initial_commit(repo: &Repository) -> Result<Oid, git2::Error> {
+    let tree = repo.find_tree(repo.index()?.write_tree()?)?;
+    let sign = signature(repo, None).map_err(|e| git2::Error::from_str(&e))?;
+    repo.set_head("refs/heads/master")?;
+    repo.commit(Some("HEAD"), &sign, &sign, "chore(init)", &tree, &[])
+}
+
+/// A path in the repository to commit, with its blob and mode for a bare repository.
+pub type CommitFile<'a> = (&'a String, Option<(Oid, FileMode)>);
+
+/// Commits `files` on the local branch, they are pushed by `push_pending`.
+/// Returns the commit, if there was anything to commit.
+/// Stages only `files`, other changes of the working tree are left alone (see `check_staged_changes`).
+/// In a bare repository, the tree is built from the blobs without any index.
+pub fn commit(
+    repo: &Repository,
+    sign: &Signature,
+    message: &str,
+    files: &[CommitFile],
+    signer: Option<&Signer>,
+) -> Result<Option<Oid>, String> {
     let parent_commit = repo
         .head()
-        .unwrap()
-        .resolve()
-        .unwrap()
-        .peel(git2::ObjectType::Commit)
-        .unwrap()
-        .into_commit()
-        .unwrap();
+        .and_then(|head| head.peel_to_commit())
+        .map_err(error_message)?;
+    let parent_tree = parent_commit.tree().map_err(error_message)?;
 
-    let mut index: Index = repo.index().unwrap();
-
-    let _ = repo
-        .diff_index_to_workdir(Some(&index), None)
-        .unwrap()
-        .print(DiffFormat::Raw, |d, h, l| {
-            println!(
-                "{:?} {:?} {:?}",
-                d,
-                h,
-                String::from_utf8(l.content().to_vec()).unwrap()
-            );
-            true
-        });
+    let oid = if repo.is_bare() {
+        let mut update = TreeUpdateBuilder::new();
+        for (file, blob) in files {
+            let (blob, mode) =
+                blob.ok_or_else(|| format!("No blob for {} in a bare repository", file))?;
+            log!("Committing {} as {}", file, blob);
+            update.upsert(file.as_str(), blob, mode);
+        }
+        update
+            .create_updated(repo, &parent_tree)
+            .map_err(error_message)?
+    } else {
+        let mut index: Index = repo.index().map_err(error_message)?;
+        for (file, _) in files {
+            log!("Staging {}", file);
+            index.add_path(Path::new(file)).map_err(error_message)?;
+        }
+        let oid = index.write_tree().map_err(error_message)?;
+        index.write().map_err(error_message)?;
+        oid
+    };
+    if oid == parent_tree.id() {
+        return Ok(None);
+    }
+    log!("tree: {:?}", oid);
+    let tree = repo.find_tree(oid).map_err(error_message)?;
+    let oid = create_commit(repo, sign, sign, message, &tree, &[&parent_commit], signer)?;
+    repo.head()
+        .and_then(|head| head.resolve())
+        .and_then(|mut head| head.set_target(oid, &format!("commit: {}", message)))
+        .map_err(error_message)?;
+    Ok(Some(oid))
+}
 
-    index
-        .add_all(["*"].iter(), IndexAddOption::FORCE, None)
-        .unwrap();
-    if index.is_empty() {
-        None
+/// Fails if changes to paths that are not `linked` are staged, as they would be committed with the linked files.
+/// Checked before any file is written.
+pub fn check_staged_changes(
+    repo: &Repository,
+    linked: impl Fn(&str) -> bool,
+) -> Result<(), String> {
+    if repo.is_bare() {
+        return Ok(());
+    }
+    let head_tree = repo.head().and_then(|head| head.peel_to_tree()).ok();
+    let index = repo.index().map_err(error_message)?;
+    let staged: Vec<String> = repo
+        .diff_tree_to_index(head_tree.as_ref(), Some(&index), None)
+        .map_err(error_message)?
+        .deltas()
+        .filter_map(|delta| delta.new_file().path().or(delta.old_file().path()))
+        .map(|path| path.to_string_lossy().into_owned())
+        .filter(|path| !linked(path))
+        .collect();
+    if staged.is_empty() {
+        Ok(())
     } else {
-        let oid = index.write_tree().unwrap();
-        println!("oid: {:?}", oid);
-        index.write().unwrap();
-        let tree = repo.find_tree(oid).unwrap();
-        println!("tree: {:?}", tree);
-        repo.commit(
-            Some("HEAD"),
-            &sign,
-            &sign,
-            "chore(append)",
-            &tree,
-            &[&parent_commit],
+        Err(format!(
+            "Changes unrelated to git-append are staged ({}), commit or unstage them, or set `allow_staged_changes`",
+            staged.join(", ")
+        ))
+    }
+}
+
+/// Creates a commit without moving any reference, signed by `signer` if any.
+fn create_commit(
+    repo: &Repository,
+    author: &Signature,
+    committer: &Signature,
+    message: &str,
+    tree: &git2::Tree,
+    parents: &[&git2::Commit],
+    signer: Option<&Signer>,
+) -> Result<Oid, String> {
+    let Some(signer) = signer else {
+        return repo
+            .commit(None, author, committer, message, tree, parents)
+            .map_err(error_message);
+    };
+    let buffer = repo
+        .commit_create_buffer(author, committer, message, tree, parents)
+        .map_err(error_message)?;
+    let content = buffer
+        .as_str()
+        .ok_or("The commit to sign is not valid UTF-8")?;
+    let signature = signer.sign(content)?;
+    repo.commit_signed(content, &signature, None)
+        .map_err(error_message)
+}
+
+/// Fetches `branch` into its remote-tracking branch, without changing the remote config.
+fn fetch(repo: &Repository, options: &RemoteOptions, branch: String) -> Result<Oid, git2::Error> {
+    let mut remote = repo.find_remote("http-origin")?;
+    let tracking = format!("refs/remotes/http-origin/{}", branch);
+    with_retries(options.retries, || {
+        let mut fetch_options = FetchOptions::default();
+        fetch_options.remote_callbacks(create_callbacks(options));
+        fetch_options.proxy_options(proxy_options(options));
+        remote.connect_auth(
+            Direction::Fetch,
+            Some(create_callbacks(options)),
+            Some(proxy_options(options)),
+        )?;
+        remote.fetch(
+            &[format!("+refs/heads/{}:{}", branch, tracking)],
+            Some(&mut fetch_options),
+            None,
         )
-        .ok()
+    })?;
+    let oid = repo.refname_to_id(&tracking)?;
+    log!("{}: {}", tracking, oid);
+    Ok(oid)
+}
+
+/// The remote could not be reached (no network, unknown host, host down, timeout), as opposed to being reached and
+/// failing: a wrong URL, an HTTP error status or a local I/O error are errors.
+fn is_unreachable(e: &git2::Error) -> bool {
+    const MESSAGES: [&str; 3] = [
+        "failed to connect to",
+        "failed to resolve address",
+        "timed out",
+    ];
+    e.code() == ErrorCode::Timeout
+        || matches!(e.class(), git2::ErrorClass::Net | git2::ErrorClass::Os)
+            && MESSAGES.iter().any(|message| e.message().contains(message))
+}
+
+/// Runs `f` again while it cannot reach the remote, up to `retries` times, waiting 1, 2, 4... seconds in between.
+fn with_retries<T>(
+    retries: u32,
+    mut f: impl FnMut() -> Result<T, git2::Error>,
+) -> Result<T, git2::Error> {
+    let mut attempt = 0;
+    loop {
+        match f() {
+            Err(e) if is_unreachable(&e) && attempt < retries => {
+                let delay = Duration::from_secs(1 << attempt.min(6));
+                log!(
+                    "Could not reach the remote ({}), retrying in {}s",
+                    e.message(),
+                    delay.as_secs()
+                );
+                thread::sleep(delay);
+                attempt += 1;
+            }
+            result => return result,
+        }
+    }
+}
+
+/// Number of local commits of `branch` not on its remote-tracking branch (every commit if it was never fetched).
+pub fn pending_commits(repo: &Repository, branch: &str) -> Result<usize, String> {
+    let Ok(local) = repo.refname_to_id(&format!("refs/heads/{}", branch)) else {
+        return Ok(0);
+    };
+    match repo.refname_to_id(&format!("refs/remotes/http-origin/{}", branch)) {
+        Ok(remote) => repo
+            .graph_ahead_behind(local, remote)
+            .map(|(ahead, _)| ahead)
+            .map_err(error_message),
+        Err(_) => {
+            let mut revwalk = repo.revwalk().map_err(error_message)?;
+            revwalk.push(local).map_err(error_message)?;
+            Ok(revwalk.count())
+        }
+    }
+}
+
+/// Whether every commit of `local` not in `remote` was made by git-append (and queued while offline).
+fn only_queued_commits(repo: &Repository, local: Oid, remote: Oid) -> Result<bool, String> {
+    let mut revwalk = repo.revwalk().map_err(error_message)?;
+    revwalk
+        .push(local)
+        .and_then(|_| revwalk.hide(remote))
+        .map_err(error_message)?;
+    for oid in revwalk {
+        let commit = oid
+            .and_then(|oid| repo.find_commit(oid))
+            .map_err(error_message)?;
+        if !commit.message().is_some_and(is_git_append_message) {
+            return Ok(false);
+        }
+    }
+    Ok(true)
+}
+
+/// Merges a file changed by queued commits, from its path, its queued content and its remote content.
+pub type Reconcile<'a> = dyn Fn(&str, &[u8], &[u8]) -> Result<Vec<u8>, String> + 'a;
+
+/// Applies the changes from `base` to `local` on `target`, every changed file being merged with `reconcile`
+/// (a textual merge would conflict on encrypted files).
+fn reconcile_tree<'r>(
+    repo: &'r Repository,
+    base: &Tree,
+    local: &Tree,
+    target: &Tree,
+    reconcile: &Reconcile,
+) -> Result<Tree<'r>, String> {
+    let diff = repo
+        .diff_tree_to_tree(Some(base), Some(local), None)
+        .map_err(error_message)?;
+    let mut update = TreeUpdateBuilder::new();
+    for delta in diff.deltas() {
+        let (Some(path), Delta::Added | Delta::Modified) =
+            (delta.new_file().path(), delta.status())
+        else {
+            continue;
+        };
+        let local_blob = repo
+            .find_blob(delta.new_file().id())
+            .map_err(error_message)?;
+        let target_entry = target.get_path(path).ok();
+        let target_content = match &target_entry {
+            Some(entry) => repo
+                .find_blob(entry.id())
+                .map_err(error_message)?
+                .content()
+                .to_vec(),
+            None => Vec::new(),
+        };
+        let merged = reconcile(
+            &path.to_string_lossy(),
+            local_blob.content(),
+            &target_content,
+        )?;
+        let oid = repo.blob(&merged).map_err(error_message)?;
+        // a mode changed by the queued commits wins
+        let mode = match &target_entry {
+            Some(entry) if delta.old_file().mode() == delta.new_file().mode() => {
+                if entry.filemode() == i32::from(FileMode::BlobExecutable) {
+                    FileMode::BlobExecutable
+                } else {
+                    FileMode::Blob
+                }
+            }
+            _ => delta.new_file().mode(),
+        };
+        update.upsert(path, oid, mode);
+    }
+    update
+        .create_updated(repo, target)
+        .and_then(|oid| repo.find_tree(oid))
+        .map_err(error_message)
+}
+
+/// Pushes the local commits of `master` missing from the remote, then pulls to update the remote-tracking branch.
+/// Returns false if the remote is unreachable, the commits stay queued until the next run.
+pub fn push_pending(
+    repo: &Repository,
+    options: &RemoteOptions,
+    policy: &PullPolicy,
+    sign: &Signature,
+    signer: Option<&Signer>,
+    reconcile: &Reconcile,
+) -> Result<bool, String> {
+    let pending = pending_commits(repo, "master")?;
+    if pending == 0 {
+        return Ok(true);
+    }
+    log!("Pushing {} commit(s)", pending);
+    let mut remote = repo.find_remote("http-origin").map_err(error_message)?;
+    match push_remote(&mut remote, options) {
+        Ok(()) => pull(
+            repo,
+            options,
+            "master".to_owned(),
+            policy,
+            sign,
+            signer,
+            reconcile,
+        ),
+        Err(e) if is_unreachable(&e) => {
+            log!(
+                "The remote is unreachable ({}), {} commit(s) queued",
+                e.message(),
+                pending
+            );
+            Ok(false)
+        }
+        Err(e) => Err(format!("Could not push: {}", describe_error(&e))),
+    }
+}
+
+/// Removes the `refs/heads/X:refs/heads/X` refspecs that older versions added to `http-origin` on every fetch.
+/// Returns the removed refspecs.
+pub fn remove_added_refspecs(repo: &Repository) -> Result<Vec<String>, git2::Error> {
+    let mut config = repo.config()?.open_level(git2::ConfigLevel::Local)?;
+    let mut added = Vec::new();
+    config
+        .multivar("remote.http-origin.fetch", None)?
+        .for_each(|entry| {
+            if let Some(refspec) = entry.value() {
+                let is_added = refspec
+                    .split_once(':')
+                    .is_some_and(|(src, dst)| src == dst && src.starts_with("refs/heads/"));
+                if is_added && !added.contains(&refspec.to_owned()) {
+                    added.push(refspec.to_owned());
+                }
+            }
+        })?;
+    for refspec in added.iter() {
+        config.remove_multivar(
+            "remote.http-origin.fetch",
+            &format!("^{}$", escape_regex(refspec)),
+        )?;
     }
+    Ok(added)
+}
+
+fn escape_regex(value: &str) -> String {
+    value
+        .chars()
+        .flat_map(|c| match c {
+            '.' | '[' | ']' | '(' | ')' | '*' | '+' | '?' | '{' | '}' | '|' | '^' | '$' | '\\' => {
+                vec!['\\', c]
+            }
+            c => vec![c],
+        })
+        .collect()
 }
 
-fn fetch(
+/// Brings the local branch to the fetched remote branch.
+/// Local changes are kept, and if the branch has diverged (local commits not pushed), `policy` decides
+/// between aborting, merging or rebasing. With `Merge` and `Rebase`, local changes conflicting with the update are stashed
+/// and restored afterwards.
+/// Commits queued by git-append while offline are merged even with `Abort`, their files being merged with `reconcile`.
+/// Returns false if the remote is unreachable, the local branch is then left as it is.
+pub fn pull(
     repo: &Repository,
-    credentials: Option<(String, String)>,
+    options: &RemoteOptions,
     branch: String,
-) -> Option<git2::AnnotatedCommit> {
-    let mut remote = repo.find_remote("http-origin").unwrap();
-    let mut fetch_options = FetchOptions::default();
-    fetch_options.remote_callbacks(create_callbacks(credentials.clone()));
-    remote
-        .connect_auth(Direction::Fetch, Some(create_callbacks(credentials)), None)
-        .unwrap();
-    repo.remote_add_fetch(
-        "http-origin",
-        &format!("refs/heads/{}:refs/heads/{}", branch, branch),
-    )
-    .unwrap();
-    remote
-        .fetch(&[&branch], Some(&mut fetch_options), None)
-        .unwrap();
-    repo.fetchhead_foreach(|name, _, _, merge| {
-        println!("{} : {}", name, merge);
-        if merge {
-            return true;
+    policy: &PullPolicy,
+    sign: &Signature,
+    signer: Option<&Signer>,
+    reconcile: &Reconcile,
+) -> Result<bool, String> {
+    let reference = format!("refs/heads/{}", branch);
+    let local = repo.refname_to_id(&reference).ok();
+    if let Some(local) = local {
+        check_out_branch(repo, &reference, local)?;
+    }
+    let remote = match fetch(repo, options, branch.clone()) {
+        Ok(remote) => remote,
+        Err(e) if is_unreachable(&e) => {
+            log!(
+                "The remote is unreachable ({}), working offline",
+                e.message()
+            );
+            return Ok(false);
         }
-        true
-    })
-    .unwrap();
-    let fetch_head = repo.find_reference("FETCH_HEAD").unwrap();
-    repo.reference_to_annotated_commit(&fetch_head).ok()
+        Err(e) => {
+            return Err(format!(
+                "Could not fetch {}: {}",
+                branch,
+                describe_error(&e)
+            ))
+        }
+    };
+    let Some(local) = local else {
+        log!("Creating {} at {}", reference, remote);
+        repo.reference(&reference, remote, false, "pull: create branch")
+            .and_then(|_| repo.set_head(&reference))
+            .map_err(error_message)?;
+        update_tree(repo, &reference, remote, policy, sign)?;
+        return Ok(true);
+    };
+    // the fetch may have moved the local branch (refspec to `refs/heads` added by older versions), it is put back first
+    if repo.refname_to_id(&reference).ok() != Some(local) {
+        repo.reference(&reference, local, true, "pull: restore local branch")
+            .map_err(error_message)?;
+    }
+
+    let (ahead, behind) = repo
+        .graph_ahead_behind(local, remote)
+        .map_err(error_message)?;
+    if behind == 0 {
+        if ahead > 0 {
+            log!("{} is {} commit(s) ahead of the remote", branch, ahead);
+        }
+        return Ok(true);
+    }
+    if ahead == 0 {
+        log!("Fast-Forward: Setting {} to id: {}", reference, remote);
+        update_tree(repo, &reference, remote, policy, sign)?;
+        return Ok(true);
+    }
+    let queued = only_queued_commits(repo, local, remote)?;
+    if queued {
+        log!(
+            "{} has {} queued commit(s) and the remote has {} new one(s)",
+            branch,
+            ahead,
+            behind
+        );
+    } else {
+        log!(
+            "{} has diverged: {} local and {} remote commit(s)",
+            branch,
+            ahead,
+            behind
+        );
+    }
+    // the queued commits only add lines, they are merged whatever the policy
+    let reconcile = queued.then_some(reconcile);
+    let target = match policy {
+        PullPolicy::Abort if !queued => return Err(format!(
+            "{} has diverged from the remote ({} local and {} remote commit(s)), merge or rebase it by hand, or set `pull_policy`",
+            branch, ahead, behind
+        )),
+        PullPolicy::Abort | PullPolicy::Merge => {
+            merge_commit(repo, local, remote, &branch, sign, signer, reconcile)?
+        }
+        PullPolicy::Rebase => rebase(repo, local, remote, &branch, sign, signer, reconcile)?,
+    };
+    update_tree(repo, &reference, target, policy, sign)?;
+    Ok(true)
+}
+
+/// Puts HEAD back on `reference` if the checkout was left on another branch, which would otherwise get the commits.
+/// Local changes made on the other branch are not carried over: the tree has to be clean.
+fn check_out_branch(repo: &Repository, reference: &str, local: Oid) -> Result<(), String> {
+    if repo.is_bare() {
+        return Ok(());
+    }
+    let head = repo
+        .find_reference("HEAD")
+        .map_err(error_message)?
+        .symbolic_target()
+        .map(str::to_owned);
+    if head.as_deref() == Some(reference) {
+        return Ok(());
+    }
+    let workdir = repo.workdir().unwrap_or(repo.path()).display();
+    let head = head.unwrap_or_else(|| "a detached HEAD".to_owned());
+    if is_dirty(repo) {
+        return Err(format!(
+            "{} is checked out on {} with local changes, commit or discard them and check {} out",
+            workdir, head, reference
+        ));
+    }
+    log!("Checking {} out instead of {}", reference, head);
+    let tree = repo
+        .find_commit(local)
+        .map_err(error_message)?
+        .into_object();
+    repo.checkout_tree(&tree, Some(git2::build::CheckoutBuilder::new().safe()))
+        .and_then(|_| repo.set_head(reference))
+        .map_err(error_message)
 }
 
-fn fast_forward(
+/// Checks `target` out without overwriting local changes, then moves the branch to it.
+/// A bare repository only has its branch moved.
+fn update_tree(
     repo: &Repository,
-    lb: &mut git2::Reference,
-    rc: &git2::AnnotatedCommit,
-) -> Result<(), git2::Error> {
-    let name = match lb.name() {
-        Some(s) => s.to_string(),
-        None => String::from_utf8_lossy(lb.name_bytes()).to_string(),
-    };
-    let msg = format!("Fast-Forward: Setting {} to id: {}", name, rc.id());
-    println!("{}", msg);
-    lb.set_target(rc.id(), &msg)?;
-    repo.set_head(&name)?;
-    repo.checkout_head(Some(
-        git2::build::CheckoutBuilder::default()
-            // For some reason the force is required to make the working directory actually get updated
-            // I suspect we should be adding some logic to handle dirty working directory states
-            // but this is just an example so maybe not.
-            .force(),
-    ))?;
+    reference: &str,
+    target: Oid,
+    policy: &PullPolicy,
+    sign: &Signature,
+) -> Result<(), String> {
+    let move_branch = || {
+        repo.reference(reference, target, true, &format!("pull: {}", target))
+            .map(|_| ())
+            .map_err(error_message)
+    };
+    if repo.is_bare() {
+        return move_branch();
+    }
+    let tree = repo
+        .find_commit(target)
+        .map_err(error_message)?
+        .into_object();
+    let checkout = |repo: &Repository| {
+        repo.checkout_tree(&tree, Some(git2::build::CheckoutBuilder::new().safe()))
+    };
+    let workdir = repo.workdir().unwrap_or(repo.path()).display();
+    let stashed = match checkout(repo) {
+        Ok(()) => false,
+        Err(e) if e.code() == ErrorCode::Conflict && policy != &PullPolicy::Abort => {
+            log!("Stashing local changes conflicting with the update");
+            let mut stash_repo = Repository::open(repo.path()).map_err(error_message)?;
+            stash_repo
+                .stash_save(sign, "git-append autostash", None)
+                .map_err(error_message)?;
+            checkout(repo).map_err(error_message)?;
+            true
+        }
+        Err(e) => return Err(format!(
+            "Local changes in {} conflict with the remote ({}), commit or discard them, or set `pull_policy`",
+            workdir,
+            e.message()
+        )),
+    };
+    move_branch()?;
+    if stashed {
+        let mut stash_repo = Repository::open(repo.path()).map_err(error_message)?;
+        let applied = stash_repo.stash_apply(0, None);
+        let conflicts = stash_repo
+            .index()
+            .map_or(true, |index| index.has_conflicts());
+        if applied.is_err() || conflicts {
+            stash_repo
+                .checkout_head(Some(git2::build::CheckoutBuilder::new().force()))
+                .map_err(error_message)?;
+            return Err(format!(
+                "Could not restore the local changes of {}, they are kept in the stash",
+                workdir
+            ));
+        }
+        stash_repo.stash_drop(0).map_err(error_message)?;
+    }
     Ok(())
 }
 
-pub fn pull(repo: &Repository, credentials: Option<(String, String)>, branch: String) {
-    let fetch_commit = fetch(repo, credentials, branch.clone()).unwrap();
-    let mut r = repo
-        .find_reference(&format!("refs/heads/{}", branch))
-        .unwrap();
-    fast_forward(repo, &mut r, &fetch_commit).unwrap();
+/// Without `reconcile`, the files are merged by git.
+fn merge_commit(
+    repo: &Repository,
+    local: Oid,
+    remote: Oid,
+    branch: &str,
+    sign: &Signature,
+    signer: Option<&Signer>,
+    reconcile: Option<&Reconcile>,
+) -> Result<Oid, String> {
+    let local_commit = repo.find_commit(local).map_err(error_message)?;
+    let remote_commit = repo.find_commit(remote).map_err(error_message)?;
+    let tree = match reconcile {
+        Some(reconcile) => {
+            let base = repo
+                .merge_base(local, remote)
+                .and_then(|base| repo.find_commit(base))
+                .and_then(|base| base.tree())
+                .map_err(error_message)?;
+            let trees = local_commit
+                .tree()
+                .and_then(|local| Ok((local, remote_commit.tree()?)));
+            let (local_tree, remote_tree) = trees.map_err(error_message)?;
+            reconcile_tree(repo, &base, &local_tree, &remote_tree, reconcile)?
+        }
+        None => {
+            let mut index = repo
+                .merge_commits(&local_commit, &remote_commit, None)
+                .map_err(error_message)?;
+            if index.has_conflicts() {
+                return Err(format!(
+                    "Merging the remote into {} conflicts, merge it by hand",
+                    branch
+                ));
+            }
+            index
+                .write_tree_to(repo)
+                .and_then(|oid| repo.find_tree(oid))
+                .map_err(error_message)?
+        }
+    };
+    create_commit(
+        repo,
+        sign,
+        sign,
+        &format!("Merge http-origin/{} into {}", branch, branch),
+        &tree,
+        &[&local_commit, &remote_commit],
+        signer,
+    )
+}
+
+/// Replays the local commits on the remote branch, in memory, keeping their author.
+/// Merge commits are left out, as `git rebase` does. Without `reconcile`, the files are merged by git.
+fn rebase(
+    repo: &Repository,
+    local: Oid,
+    remote: Oid,
+    branch: &str,
+    sign: &Signature,
+    signer: Option<&Signer>,
+    reconcile: Option<&Reconcile>,
+) -> Result<Oid, String> {
+    let mut revwalk = repo.revwalk().map_err(error_message)?;
+    revwalk
+        .set_sorting(git2::Sort::TOPOLOGICAL | git2::Sort::REVERSE)
+        .and_then(|_| revwalk.push(local))
+        .and_then(|_| revwalk.hide(remote))
+        .map_err(error_message)?;
+    let mut target = repo.find_commit(remote).map_err(error_message)?;
+    for oid in revwalk {
+        let commit = oid
+            .and_then(|oid| repo.find_commit(oid))
+            .map_err(error_message)?;
+        if commit.parent_count() > 1 {
+            continue;
+        }
+        let tree = match reconcile {
+            Some(reconcile) => {
+                let trees = commit.tree().and_then(|tree| {
+                    let base = match commit.parent(0) {
+                        Ok(parent) => parent.tree()?,
+                        Err(_) => repo.find_tree(repo.treebuilder(None)?.write()?)?,
+                    };
+                    Ok((base, tree, target.tree()?))
+                });
+                let (base, tree, target_tree) = trees.map_err(error_message)?;
+                reconcile_tree(repo, &base, &tree, &target_tree, reconcile)?
+            }
+            None => {
+                let mut index = repo
+                    .cherrypick_commit(&commit, &target, 0, None)
+                    .map_err(error_message)?;
+                if index.has_conflicts() {
+                    return Err(format!(
+                        "Rebasing {} on the remote conflicts, rebase it by hand",
+                        branch
+                    ));
+                }
+                index
+                    .write_tree_to(repo)
+                    .and_then(|oid| repo.find_tree(oid))
+                    .map_err(error_message)?
+            }
+        };
+        // already in the remote branch
+        if tree.id() == target.tree_id() {
+            continue;
+        }
+        let oid = create_commit(
+            repo,
+            &commit.author(),
+            sign,
+            commit.message().unwrap_or_default(),
+            &tree,
+            &[&target],
+            signer,
+        )?;
+        target = repo.find_commit(oid).map_err(error_message)?;
+    }
+    Ok(target.id())
 }
 
-pub fn signature() -> Signature<'static> {
-    Signature::now("Git-Append", "git-append@git").unwrap()
+/// The identity from the config, or from `user.name` and `user.email`, or made from the hostname.
+pub fn signature(repo: &Repository, author: Option<&Author>) -> Result<Signature<'static>, String> {
+    let repo_config = repo.config().ok();
+    let value = |configured: Option<&String>, key: &str| {
+        configured
+            .cloned()
+            .or_else(|| repo_config.as_ref()?.get_string(key).ok())
+    };
+    let hostname = hostname();
+    let name = value(author.and_then(|a| a.name.as_ref()), "user.name")
+        .unwrap_or(format!("Git-Append on {}", hostname));
+    let email = value(author.and_then(|a| a.email.as_ref()), "user.email")
+        .unwrap_or(format!("git-append@{}", hostname));
+    Signature::now(&name, &email)
+        .map_err(|e| format!("Invalid author {} <{}>: {}", name, email, e.message()))
 }
 
-fn push(repo: &Repository, credentials: Option<(String, String)>) {
-    let mut remote = repo.find_remote("http-origin").unwrap();
-    println!("URL: {:?}", remote.url());
-    // repo.remote_add_push("origin", "refs/heads/master:refs/heads/master")
-    //     .unwrap();
+/// Pushes `master` to a mirror, without adding it to the remotes of the repository.
+pub fn push_mirror(
+    repo: &Repository,
+    url: &str,
+    options: &RemoteOptions,
+) -> Result<(), git2::Error> {
+    let mut remote = repo.remote_anonymous(url)?;
+    push_remote(&mut remote, options)
+}
 
-    remote
-        .connect_auth(
+/// A rejected reference (not a fast-forward) is an error.
+fn push_remote(remote: &mut Remote, options: &RemoteOptions) -> Result<(), git2::Error> {
+    log!("URL: {:?}", remote.url());
+    with_retries(options.retries, || {
+        remote.connect_auth(
             Direction::Push,
-            Some(create_callbacks(credentials.clone())),
-            None,
-        )
-        .unwrap();
-    // repo.remote_add_push("origin", "refs/heads/master:refs/heads/master")
-    //     .unwrap();
-    let mut push_options = PushOptions::default();
-    let callbacks = create_callbacks(credentials.clone());
-    push_options.remote_callbacks(callbacks);
-
-    remote
-        .push(
+            Some(create_callbacks(options)),
+            Some(proxy_options(options)),
+        )?;
+        let mut push_options = PushOptions::default();
+        let mut callbacks = create_callbacks(options);
+        callbacks.push_update_reference(|reference, status| match status {
+            Some(message) => Err(git2::Error::from_str(&format!(
+                "{} was rejected: {}",
+                reference, message
+            ))),
+            None => Ok(()),
+        });
+        push_options.remote_callbacks(callbacks);
+        push_options.proxy_options(proxy_options(options));
+        remote.push(
             &["refs/heads/master:refs/heads/master"],
             Some(&mut push_options),
         )
-        .unwrap();
+    })
+}
+
+fn proxy_options<'a>(options: &RemoteOptions) -> ProxyOptions<'a> {
+    let mut proxy = ProxyOptions::new();
+    match &options.proxy {
+        Some(url) => proxy.url(url),
+        None => proxy.auto(),
+    };
+    proxy
 }
 
-fn create_callbacks<'a>(credentials: Option<(String, String)>) -> RemoteCallbacks<'a> {
-    if let Some((username, token)) = credentials.clone() {
+fn create_callbacks<'a>(options: &RemoteOptions) -> RemoteCallbacks<'a> {
+    let mut callbacks = if let Some((username, token)) = options.credentials.clone() {
         create_callbacks_with_creds(username, token)
     } else {
         RemoteCallbacks::new()
+    };
+    if let Some(fingerprint) = options.certificate_sha256.clone() {
+        callbacks.certificate_check(move |cert, host| check_pin(cert, host, &fingerprint));
+    }
+    callbacks
+}
+
+/// Accepts the certificate only if its fingerprint is the pinned one, whoever signed it.
+fn check_pin(
+    cert: &git2::cert::Cert,
+    host: &str,
+    fingerprint: &str,
+) -> Result<CertificateCheckStatus, git2::Error> {
+    check_fingerprint(cert.as_x509().map(|x509| x509.data()), host, fingerprint)
+}
+
+/// `der` is the DER encoding of the TLS certificate, `None` for an SSH host key.
+fn check_fingerprint(
+    der: Option<&[u8]>,
+    host: &str,
+    fingerprint: &str,
+) -> Result<CertificateCheckStatus, git2::Error> {
+    let refused = |message: String| {
+        git2::Error::new(ErrorCode::Certificate, git2::ErrorClass::Callback, message)
+    };
+    let expected = parse_sha256(fingerprint).map_err(refused)?;
+    let Some(der) = der else {
+        // not a TLS certificate (SSH host key), left to the usual checks
+        return Ok(CertificateCheckStatus::CertificatePassthrough);
+    };
+    let actual = Sha256::digest(der);
+    if actual.as_slice() == expected.as_slice() {
+        Ok(CertificateCheckStatus::CertificateOk)
+    } else {
+        Err(refused(format!(
+            "The certificate of {} does not match `certificate_sha256`: expected {}, got {}",
+            host,
+            format_sha256(&expected),
+            format_sha256(&actual)
+        )))
     }
 }
 
@@ -172,29 +908,666 @@ fn create_callbacks_with_creds<'a>(username: String, token: String) -> RemoteCal
     callbacks
 }
 
-pub fn get_blob_from_head(repo: &Repository, path: &String, branch_name: String) -> Vec<u8> {
-    let parent_commit = repo
+pub fn get_blob_from_head(
+    repo: &Repository,
+    path: &String,
+    reference: &str,
+) -> Result<Vec<u8>, String> {
+    let tree = repo
+        .find_reference(reference)
+        .and_then(|reference| reference.peel_to_tree())
+        .map_err(|e| format!("Could not read {}: {}", reference, e.message()))?;
+    let Ok(entry) = tree.get_path(Path::new(&path)) else {
+        return Ok(Vec::new());
+    };
+    let blob = entry
+        .to_object(repo)
+        .map_err(error_message)?
+        .into_blob()
+        .map_err(|_| format!("{} is not a file in {}", path, reference))?;
+    Ok(blob.content().into())
+}
+
+/// The blob and mode of the file in the tree of `reference`, `None` if it is not there.
+pub fn tree_entry(repo: &Repository, path: &String, reference: &str) -> Option<(Oid, i32)> {
+    let entry = repo
+        .find_reference(reference)
+        .ok()?
+        .peel_to_tree()
+        .ok()?
+        .get_path(Path::new(path))
+        .ok()?;
+    Some((entry.id(), entry.filemode()))
+}
+
+/// The blob and mode of the file in a commit, `None` if it is not there.
+pub fn commit_entry(repo: &Repository, commit: Oid, path: &String) -> Option<(Oid, i32)> {
+    let entry = repo
+        .find_commit(commit)
+        .ok()?
+        .tree()
+        .ok()?
+        .get_path(Path::new(path))
+        .ok()?;
+    Some((entry.id(), entry.filemode()))
+}
+
+/// The last commit of the remote branch that changed `path`.
+pub fn last_change<'a>(
+    repo: &'a Repository,
+    path: &String,
+    branch_name: String,
+) -> Option<git2::Commit<'a>> {
+    let tip = repo
         .find_branch(&branch_name, git2::BranchType::Remote)
+        .ok()?
+        .into_reference()
+        .peel_to_commit()
+        .ok()?;
+    let mut revwalk = repo.revwalk().ok()?;
+    revwalk.push(tip.id()).ok()?;
+    let path = Path::new(path);
+    let entry_id = |commit: &git2::Commit| {
+        commit
+            .tree()
+            .ok()
+            .and_then(|tree| tree.get_path(path).ok())
+            .map(|entry| entry.id())
+    };
+    revwalk
+        .filter_map(|oid| repo.find_commit(oid.ok()?).ok())
+        .find(|commit| {
+            let id = entry_id(commit);
+            id.is_some() && id != commit.parent(0).ok().and_then(|p| entry_id(&p))
+        })
+}
+
+/// Whether the working tree or the index has changes, ignored files excluded.
+/// A bare repository is never dirty.
+pub fn is_dirty(repo: &Repository) -> bool {
+    if repo.is_bare() {
+        return false;
+    }
+    repo.statuses(None)
+        .map(|statuses| {
+            statuses
+                .iter()
+                .any(|s| s.status() != git2::Status::CURRENT && s.status() != git2::Status::IGNORED)
+        })
+        .unwrap_or(true)
+}
+
+#[cfg(test)]
+pub mod tests {
+    use pretty_assertions::assert_eq;
+    use std::fs;
+    use std::path::{Path, PathBuf};
+
+    use git2::{CertificateCheckStatus, ErrorClass, ErrorCode, FileMode, Repository};
+    use sha2::{Digest, Sha256};
+    use tempfile::TempDir;
+
+    use crate::config::GitAppender;
+    use crate::config::{Author, PullPolicy};
+    use crate::core::reconcile;
+    use crate::git::{
+        check_fingerprint, check_staged_changes, clone, commit, format_sha256, initial_commit,
+        is_unreachable, open_or_init_bare, parse_sha256, pending_commits, pull, push_pending,
+        remove_added_refspecs, signature, with_retries, RemoteOptions,
+    };
+    use crate::message::commit_message;
+    use crate::signing::tests::ssh_signer;
+
+    fn lines(path: &str, local: &[u8], remote: &[u8]) -> Result<Vec<u8>, String> {
+        reconcile(&GitAppender::default(), path, local, remote)
+    }
+
+    /// A temporary folder holding an empty bare `remote.git`, removed when dropped.
+    pub struct Fixture {
+        pub folder: TempDir,
+        pub url: String,
+    }
+
+    impl Fixture {
+        pub fn new() -> Self {
+            let folder = tempfile::tempdir().unwrap();
+            let url = folder
+                .path()
+                .join("remote.git")
+                .to_string_lossy()
+                .into_owned();
+            Repository::init_bare(&url).unwrap();
+            Fixture { folder, url }
+        }
+
+        pub fn join(&self, name: &str) -> PathBuf {
+            self.folder.path().join(name)
+        }
+
+        pub fn path(&self, name: &str) -> String {
+            self.join(name).to_string_lossy().into_owned()
+        }
+
+        /// Clones the remote into `name`.
+        pub fn clone(&self, name: &str) -> Repository {
+            clone(&self.url, &self.join(name), &RemoteOptions::default()).unwrap()
+        }
+    }
+
+    #[test]
+    fn test_commit_only_given_files() {
+        let folder = tempfile::tempdir().unwrap();
+        let repo = Repository::init(folder.path()).unwrap();
+        initial_commit(&repo).unwrap();
+        fs::write(folder.path().join("synced"), "a\n").unwrap();
+        fs::write(folder.path().join("unrelated"), "b\n").unwrap();
+        let synced = String::from("synced");
+
+        let oid = commit(
+            &repo,
+            &signature(&repo, None).unwrap(),
+            "chore(append)",
+            &[(&synced, None)],
+            None,
+        )
+        .unwrap()
         .unwrap();
-    let head_commit = parent_commit.into_reference().peel_to_commit().unwrap();
-    let path = Path::new(&path);
-    let maybe_path = head_commit
-        .as_object()
-        .clone()
-        .into_commit()
+        let tree = repo.find_commit(oid).unwrap().tree().unwrap();
+        assert_eq!(1, tree.len());
+        assert!(tree.get_path(Path::new("synced")).is_ok());
+        assert_eq!(
+            Ok(None),
+            commit(
+                &repo,
+                &signature(&repo, None).unwrap(),
+                "chore(append)",
+                &[(&synced, None)],
+                None
+            )
+        );
+
+        let mut index = repo.index().unwrap();
+        index.add_path(Path::new("unrelated")).unwrap();
+        index.write().unwrap();
+        assert!(check_staged_changes(&repo, |path| path == "synced").is_err());
+        assert_eq!(
+            Ok(()),
+            check_staged_changes(&repo, |path| path == "synced" || path == "unrelated")
+        );
+        fs::write(folder.path().join("synced"), "a\nc\n").unwrap();
+        let oid = commit(
+            &repo,
+            &signature(&repo, None).unwrap(),
+            "chore(append)",
+            &[(&synced, None)],
+            None,
+        )
         .unwrap()
-        .tree()
+        .unwrap();
+        assert_eq!(2, repo.find_commit(oid).unwrap().tree().unwrap().len());
+    }
+
+    #[test]
+    fn test_signature() {
+        let folder = tempfile::tempdir().unwrap();
+        let repo = Repository::init(folder.path()).unwrap();
+        let mut config = repo.config().unwrap();
+        config.set_str("user.name", "Someone").unwrap();
+        config.set_str("user.email", "someone@example.com").unwrap();
+
+        let sign = signature(&repo, None).unwrap();
+        assert_eq!(
+            (Some("Someone"), Some("someone@example.com")),
+            (sign.name(), sign.email())
+        );
+        let author = Author {
+            name: Some("Laptop".to_owned()),
+            email: None,
+        };
+        let sign = signature(&repo, Some(&author)).unwrap();
+        assert_eq!(
+            (Some("Laptop"), Some("someone@example.com")),
+            (sign.name(), sign.email())
+        );
+    }
+
+    #[test]
+    fn test_remove_added_refspecs() {
+        let folder = tempfile::tempdir().unwrap();
+        let repo = Repository::init(folder.path()).unwrap();
+        repo.remote("http-origin", "https://example.com/repo.git")
+            .unwrap();
+        for _ in 0..3 {
+            repo.remote_add_fetch("http-origin", "refs/heads/master:refs/heads/master")
+                .unwrap();
+        }
+        repo.remote_add_fetch("http-origin", "refs/heads/main:refs/heads/main")
+            .unwrap();
+
+        assert_eq!(
+            vec![
+                "refs/heads/master:refs/heads/master",
+                "refs/heads/main:refs/heads/main"
+            ],
+            remove_added_refspecs(&repo).unwrap()
+        );
+        let remote = repo.find_remote("http-origin").unwrap();
+        assert_eq!(
+            vec![Some("+refs/heads/*:refs/remotes/http-origin/*")],
+            remote.fetch_refspecs().unwrap().iter().collect::<Vec<_>>()
+        );
+    }
+
+    #[test]
+    fn test_offline() {
+        let folder = tempfile::tempdir().unwrap();
+        let repo = Repository::init(folder.path()).unwrap();
+        initial_commit(&repo).unwrap();
+        // nothing listens on port 1
+        repo.remote("http-origin", "http://127.0.0.1:1/repo.git")
+            .unwrap();
+        let sign = signature(&repo, None).unwrap();
+
+        assert_eq!(
+            Ok(false),
+            pull(
+                &repo,
+                &RemoteOptions::default(),
+                "master".to_owned(),
+                &PullPolicy::Abort,
+                &sign,
+                None,
+                &lines
+            )
+        );
+        fs::write(folder.path().join("synced"), "a\n").unwrap();
+        let synced = String::from("synced");
+        commit(&repo, &sign, "chore(append)", &[(&synced, None)], None).unwrap();
+        assert_eq!(
+            Ok(false),
+            push_pending(
+                &repo,
+                &RemoteOptions::default(),
+                &PullPolicy::Abort,
+                &sign,
+                None,
+                &lines
+            )
+        );
+        // never fetched, the initial commit is pending too
+        assert_eq!(Ok(2), pending_commits(&repo, "master"));
+    }
+
+    #[test]
+    fn test_is_unreachable() {
+        let error = |class, message| git2::Error::new(ErrorCode::GenericError, class, message);
+        assert!(is_unreachable(&error(
+            ErrorClass::Os,
+            "failed to connect to 127.0.0.1: Connection refused"
+        )));
+        assert!(is_unreachable(&error(
+            ErrorClass::Net,
+            "failed to resolve address for nowhere.invalid: Name or service not known"
+        )));
+        assert!(is_unreachable(&git2::Error::new(
+            ErrorCode::Timeout,
+            ErrorClass::Net,
+            "timed out"
+        )));
+        assert!(!is_unreachable(&error(
+            ErrorClass::Http,
+            "unexpected http status code: 404"
+        )));
+        assert!(!is_unreachable(&error(
+            ErrorClass::Os,
+            "could not open 'objects/pack': Permission denied"
+        )));
+
+        let folder = tempfile::tempdir().unwrap();
+        let repo = Repository::init(folder.path()).unwrap();
+        let missing = folder
+            .path()
+            .join("missing.git")
+            .to_string_lossy()
+            .into_owned();
+        repo.remote("http-origin", &missing).unwrap();
+        let sign = signature(&repo, None).unwrap();
+        // a wrong URL is an error, not offline
+        assert!(pull(
+            &repo,
+            &RemoteOptions::default(),
+            "master".to_owned(),
+            &PullPolicy::Abort,
+            &sign,
+            None,
+            &lines
+        )
+        .is_err());
+    }
+
+    #[test]
+    fn test_pull_diverged() {
+        let fixture = Fixture::new();
+        let options = RemoteOptions::default();
+        let signer = ssh_signer(fixture.folder.path());
+        let upstream = fixture.clone("upstream");
+        let sign = signature(&upstream, None).unwrap();
+        let commit_file = |repo: &Repository, name: &str, content: &str, message: &str| {
+            fs::write(repo.workdir().unwrap().join(name), content).unwrap();
+            commit(repo, &sign, message, &[(&name.to_owned(), None)], None)
+                .unwrap()
+                .unwrap()
+        };
+        commit_file(&upstream, "shared", "1\n2\n3\n4\n5\n", "shared");
+        push_pending(&upstream, &options, &PullPolicy::Abort, &sign, None, &lines).unwrap();
+
+        for policy in [PullPolicy::Abort, PullPolicy::Merge, PullPolicy::Rebase] {
+            let name = format!("{:?}", policy);
+            let local = fixture.clone(&name);
+            let shared = format!("1 {}\n2\n3\n4\n5\n", name);
+            commit_file(&upstream, "shared", &shared, "remote change");
+            push_pending(&upstream, &options, &PullPolicy::Abort, &sign, None, &lines).unwrap();
+            let local_commit = commit_file(&local, "notes", "a\n", "local change");
+            // conflicts with the update, it is stashed and restored
+            let shared_path = local.workdir().unwrap().join("shared");
+            let previous = fs::read_to_string(&shared_path).unwrap();
+            fs::write(&shared_path, previous.replace("5\n", "5 local\n")).unwrap();
+
+            let pulled = pull(
+                &local,
+                &options,
+                "master".to_owned(),
+                &policy,
+                &sign,
+                Some(&signer),
+                &lines,
+            );
+            let head = local.head().unwrap().peel_to_commit().unwrap();
+            let remote = local
+                .refname_to_id("refs/remotes/http-origin/master")
+                .unwrap();
+            let parents: Vec<_> = head.parent_ids().collect();
+            match policy {
+                PullPolicy::Abort => {
+                    assert!(pulled.is_err());
+                    assert_eq!(local_commit, head.id());
+                    continue;
+                }
+                PullPolicy::Merge => assert_eq!(vec![local_commit, remote], parents),
+                PullPolicy::Rebase => {
+                    assert_eq!(vec![remote], parents);
+                    assert_eq!(Some("local change"), head.message());
+                }
+            }
+            assert_eq!(Ok(true), pulled);
+            assert!(local.extract_signature(&head.id(), None).is_ok());
+            assert_eq!(
+                format!("1 {}\n2\n3\n4\n5 local\n", name),
+                fs::read_to_string(&shared_path).unwrap()
+            );
+            assert!(head.tree().unwrap().get_path(Path::new("notes")).is_ok());
+        }
+    }
+
+    #[test]
+    fn test_pull_queued() {
+        let fixture = Fixture::new();
+        let options = RemoteOptions::default();
+        let upstream = fixture.clone("upstream");
+        let sign = signature(&upstream, None).unwrap();
+        let history = String::from("history");
+        let commit_file = |repo: &Repository, content: &str| {
+            fs::write(repo.workdir().unwrap().join(&history), content).unwrap();
+            let message = commit_message(None, "history", &[]);
+            commit(repo, &sign, &message, &[(&history, None)], None)
+                .unwrap()
+                .unwrap()
+        };
+        commit_file(&upstream, "a\nc\n");
+        push_pending(&upstream, &options, &PullPolicy::Abort, &sign, None, &lines).unwrap();
+
+        for policy in [PullPolicy::Abort, PullPolicy::Rebase] {
+            let name = format!("{:?}", policy);
+            let local = fixture.clone(&name);
+            let previous = fs::read_to_string(local.workdir().unwrap().join(&history)).unwrap();
+            // queued while offline, the line only exists in this commit
+            let queued = commit_file(&local, &previous.replace("c\n", &format!("{}\nc\n", name)));
+            let remote_lines = format!("{}remote {}\n", previous, name);
+            commit_file(
+                &upstream,
+                &remote_lines.replace("c\n", &format!("b{}\nc\n", name)),
+            );
+            push_pending(&upstream, &options, &PullPolicy::Abort, &sign, None, &lines).unwrap();
+
+            assert_eq!(
+                Ok(true),
+                pull(
+                    &local,
+                    &options,
+                    "master".to_owned(),
+                    &policy,
+                    &sign,
+                    None,
+                    &lines
+                )
+            );
+            let head = local.head().unwrap().peel_to_commit().unwrap();
+            let remote = local
+                .refname_to_id("refs/remotes/http-origin/master")
+                .unwrap();
+            let parents: Vec<_> = head.parent_ids().collect();
+            match policy {
+                PullPolicy::Rebase => assert_eq!(vec![remote], parents),
+                _ => assert_eq!(vec![queued, remote], parents),
+            }
+            let content = fs::read_to_string(local.workdir().unwrap().join(&history)).unwrap();
+            for line in [
+                name.clone(),
+                format!("b{}", name),
+                format!("remote {}", name),
+            ] {
+                assert!(
+                    content.lines().any(|l| l == line),
+                    "{} in {}",
+                    line,
+                    content
+                );
+            }
+        }
+    }
+
+    #[test]
+    fn test_pull_other_branch() {
+        let fixture = Fixture::new();
+        let options = RemoteOptions::default();
+        let upstream = fixture.clone("upstream");
+        let sign = signature(&upstream, None).unwrap();
+        let history = String::from("history");
+        let commit_file = |repo: &Repository, content: &str| {
+            fs::write(repo.workdir().unwrap().join(&history), content).unwrap();
+            let message = commit_message(None, "history", &[]);
+            commit(repo, &sign, &message, &[(&history, None)], None)
+                .unwrap()
+                .unwrap()
+        };
+        let push =
+            || push_pending(&upstream, &options, &PullPolicy::Abort, &sign, None, &lines).unwrap();
+        commit_file(&upstream, "a\n");
+        push();
+        let local = fixture.clone("local");
+        let master = local.head().unwrap().peel_to_commit().unwrap();
+        local.branch("other", &master, false).unwrap();
+        local.set_head("refs/heads/other").unwrap();
+        let pull_master = || {
+            pull(
+                &local,
+                &options,
+                "master".to_owned(),
+                &PullPolicy::Merge,
+                &sign,
+                None,
+                &lines,
+            )
+        };
+
+        commit_file(&upstream, "a\nb\n");
+        push();
+        let readme = local.workdir().unwrap().join("README");
+        fs::write(&readme, "local change").unwrap();
+        assert!(pull_master().unwrap_err().contains("refs/heads/other"));
+        assert_eq!(
+            Some(master.id()),
+            local.refname_to_id("refs/heads/other").ok()
+        );
+
+        fs::remove_file(&readme).unwrap();
+        assert_eq!(Ok(true), pull_master());
+        let remote = local
+            .refname_to_id("refs/remotes/http-origin/master")
+            .unwrap();
+        assert_eq!(Some("refs/heads/master"), local.head().unwrap().name());
+        assert_eq!(Ok(remote), local.refname_to_id("refs/heads/master"));
+        assert_eq!(Ok(master.id()), local.refname_to_id("refs/heads/other"));
+        assert_eq!(
+            "a\nb\n",
+            fs::read_to_string(local.workdir().unwrap().join(&history)).unwrap()
+        );
+    }
+
+    #[test]
+    fn test_bare() {
+        let folder = tempfile::tempdir().unwrap();
+        let upstream_folder = folder.path().join("upstream");
+        let upstream = Repository::init(&upstream_folder).unwrap();
+        initial_commit(&upstream).unwrap();
+        let bare_folder = folder.path().join("bare").to_string_lossy().into_owned();
+
+        assert!(open_or_init_bare(&bare_folder, None).is_err());
+        let url = upstream_folder.to_string_lossy().into_owned();
+        let repo = open_or_init_bare(&bare_folder, Some(&url)).unwrap();
+        assert!(repo.is_bare());
+        assert_eq!(
+            Some(url.as_str()),
+            repo.find_remote("http-origin").unwrap().url()
+        );
+        // opened as it is once created
+        let repo = open_or_init_bare(&bare_folder, None).unwrap();
+        let sign = signature(&repo, None).unwrap();
+        assert_eq!(
+            Ok(true),
+            pull(
+                &repo,
+                &RemoteOptions::default(),
+                "master".to_owned(),
+                &PullPolicy::Abort,
+                &sign,
+                None,
+                &lines
+            )
+        );
+
+        let synced = String::from("synced");
+        let blob = repo.blob(b"a\n").unwrap();
+        let oid = commit(
+            &repo,
+            &sign,
+            "chore(append)",
+            &[(&synced, Some((blob, FileMode::BlobExecutable)))],
+            None,
+        )
         .unwrap()
-        .get_path(path.into())
-        .ok();
-    if let Some(tree) = maybe_path {
-        tree.to_object(repo)
+        .unwrap();
+        assert_eq!(Some(oid), repo.head().unwrap().target());
+        let entry = repo
+            .find_commit(oid)
             .unwrap()
-            .into_blob()
+            .tree()
             .unwrap()
-            .content()
-            .into()
-    } else {
-        Vec::new()
+            .get_path(Path::new("synced"))
+            .unwrap();
+        assert_eq!(
+            (blob, i32::from(FileMode::BlobExecutable)),
+            (entry.id(), entry.filemode())
+        );
+        assert!(commit(&repo, &sign, "chore(append)", &[(&synced, None)], None).is_err());
+    }
+
+    #[test]
+    fn test_with_retries() {
+        let unreachable = || {
+            git2::Error::new(
+                ErrorCode::GenericError,
+                ErrorClass::Os,
+                "failed to connect to 127.0.0.1",
+            )
+        };
+        let mut attempts = 0;
+        assert!(with_retries(0, || {
+            attempts += 1;
+            Err::<(), _>(unreachable())
+        })
+        .is_err());
+        assert_eq!(1, attempts);
+
+        attempts = 0;
+        assert_eq!(
+            Ok(2),
+            with_retries(3, || {
+                attempts += 1;
+                if attempts == 1 {
+                    Err(unreachable())
+                } else {
+                    Ok(attempts)
+                }
+            })
+            .map_err(|e| e.message().to_owned())
+        );
+
+        // reached but failing, not retried
+        attempts = 0;
+        assert!(with_retries(3, || {
+            attempts += 1;
+            Err::<(), _>(git2::Error::new(
+                ErrorCode::GenericError,
+                ErrorClass::Http,
+                "unexpected http status code: 404",
+            ))
+        })
+        .is_err());
+        assert_eq!(1, attempts);
+    }
+
+    #[test]
+    fn test_check_fingerprint() {
+        let der = b"certificate".as_slice();
+        let fingerprint = format_sha256(&Sha256::digest(der));
+        assert!(matches!(
+            check_fingerprint(Some(der), "example.com", &fingerprint),
+            Ok(CertificateCheckStatus::CertificateOk)
+        ));
+        assert!(matches!(
+            check_fingerprint(None, "example.com", &fingerprint),
+            Ok(CertificateCheckStatus::CertificatePassthrough)
+        ));
+        let error = check_fingerprint(Some(b"other".as_slice()), "example.com", &fingerprint)
+            .err()
+            .unwrap();
+        assert_eq!(ErrorCode::Certificate, error.code());
+        assert!(error.message().contains(&fingerprint));
+        assert!(check_fingerprint(Some(der), "example.com", "AB:CD").is_err());
+    }
+
+    #[test]
+    fn test_parse_sha256() {
+        let fingerprint = "AB:CD:EF:01:23:45:67:89:AB:CD:EF:01:23:45:67:89:AB:CD:EF:01:23:45:67:89:AB:CD:EF:01:23:45:67:89";
+        let digest = parse_sha256(fingerprint).unwrap();
+        assert_eq!(32, digest.len());
+        assert_eq!(fingerprint, format_sha256(&digest));
+        assert_eq!(
+            Ok(digest),
+            parse_sha256(&fingerprint.replace(':', "").to_lowercase())
+        );
+        assert!(parse_sha256("AB:CD").is_err());
+        assert!(parse_sha256(&"zz".repeat(32)).is_err());
     }
 }
@@ -0,0 +1,188 @@
+use std::env;
+use std::fmt;
+use std::fs::{self, File, OpenOptions};
+use std::io;
+use std::path::{Path, PathBuf};
+use std::thread;
+use std::time::{Duration, Instant};
+
+use fs2::FileExt;
+
+use crate::log;
+
+/// What to do when another git-append process holds a lock.
+#[derive(Debug, Clone, Copy, Eq, PartialEq, clap::ValueEnum)]
+pub enum OnLocked {
+    Wait,
+    Skip,
+}
+
+#[derive(Debug, Clone, Copy)]
+pub struct LockOptions {
+    pub on_locked: OnLocked,
+    /// How long to wait for a lock before giving up.
+    pub timeout: Duration,
+}
+
+impl Default for LockOptions {
+    fn default() -> Self {
+        LockOptions {
+            on_locked: OnLocked::Wait,
+            timeout: Duration::from_secs(60),
+        }
+    }
+}
+
+#[derive(Debug, Eq, PartialEq)]
+pub enum LockError {
+    /// The lock is held and `OnLocked::Skip` is set.
+    Skipped(String),
+    /// The lock is still held after waiting for the timeout.
+    Timeout(String),
+    Io(String),
+}
+
+impl fmt::Display for LockError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            LockError::Skipped(message) => write!(f, "Skipped, {}", message),
+            LockError::Timeout(message) => write!(f, "Timed out, {}", message),
+            LockError::Io(message) => write!(f, "Could not lock {}", message),
+        }
+    }
+}
+
+/// An advisory lock (`flock`) on a lock file, released when dropped or when its process exits.
+/// The lock file itself is left in place, removing it would let two runs lock different files.
+#[derive(Debug)]
+pub struct Lock {
+    _file: File,
+}
+
+impl Lock {
+    pub fn acquire(path: &Path, options: &LockOptions) -> Result<Lock, LockError> {
+        let io_error = |e: io::Error| LockError::Io(format!("{}: {}", path.display(), e));
+        let file = OpenOptions::new()
+            .write(true)
+            .create(true)
+            .truncate(false)
+            .open(path)
+            .map_err(io_error)?;
+        let start = Instant::now();
+        loop {
+            match file.try_lock_exclusive() {
+                Ok(()) => return Ok(Lock { _file: file }),
+                Err(e) if e.kind() == fs2::lock_contended_error().kind() => {
+                    let locked = format!("{} is locked by another run", path.display());
+                    if options.on_locked == OnLocked::Skip {
+                        return Err(LockError::Skipped(locked));
+                    }
+                    if start.elapsed() >= options.timeout {
+                        return Err(LockError::Timeout(locked));
+                    }
+                    log!("{}, waiting", locked);
+                    thread::sleep(Duration::from_millis(500));
+                }
+                Err(e) => return Err(io_error(e)),
+            }
+        }
+    }
+}
+
+/// Lock of an appender repository, in its `.git` folder, or in the folder itself for a bare repository
+/// (only the folder of a bare repository is created if missing, before the repository is).
+pub fn repository_lock_path(git_folder: &str, bare: bool) -> Result<PathBuf, LockError> {
+    let dot_git = Path::new(git_folder).join(".git");
+    if dot_git.is_dir() {
+        return Ok(dot_git.join("git-append.lock"));
+    }
+    if bare {
+        fs::create_dir_all(git_folder)
+            .map_err(|e| LockError::Io(format!("{}: {}", git_folder, e)))?;
+    } else if !Path::new(git_folder).is_dir() {
+        return Err(LockError::Io(format!("{}: no such repository", git_folder)));
+    }
+    Ok(Path::new(git_folder).join("git-append.lock"))
+}
+
+/// Lock of a local linked file, kept out of the folder of the file (which is created if missing).
+pub fn file_lock_path(local_path: &str) -> Result<PathBuf, LockError> {
+    let folder = env::var("XDG_RUNTIME_DIR")
+        .map(PathBuf::from)
+        .unwrap_or(env::temp_dir())
+        .join("git-append");
+    fs::create_dir_all(&folder)
+        .map_err(|e| LockError::Io(format!("{}: {}", folder.display(), e)))?;
+    let name = local_path.replace('%', "%25").replace('/', "%2F");
+    Ok(folder.join(format!("{}.lock", name)))
+}
+
+#[cfg(test)]
+pub mod tests {
+    use pretty_assertions::assert_eq;
+    use std::fs;
+    use std::time::Duration;
+
+    use crate::lock::{repository_lock_path, Lock, LockError, LockOptions, OnLocked};
+
+    #[test]
+    fn test_lock() {
+        let path =
+            std::env::temp_dir().join(format!("git-append-test-{}.lock", std::process::id()));
+        let skip = LockOptions {
+            on_locked: OnLocked::Skip,
+            timeout: Duration::from_secs(0),
+        };
+
+        let lock = Lock::acquire(&path, &skip).unwrap();
+        assert!(matches!(
+            Lock::acquire(&path, &skip),
+            Err(LockError::Skipped(_))
+        ));
+        assert!(matches!(
+            Lock::acquire(
+                &path,
+                &LockOptions {
+                    on_locked: OnLocked::Wait,
+                    ..skip
+                }
+            ),
+            Err(LockError::Timeout(_))
+        ));
+        drop(lock);
+
+        // a lock file left by an earlier run is reused
+        assert!(path.exists());
+        let lock = Lock::acquire(&path, &skip).unwrap();
+        assert!(matches!(
+            Lock::acquire(&path, &skip),
+            Err(LockError::Skipped(_))
+        ));
+        drop(lock);
+
+        fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn test_repository_lock_path() {
+        let folder =
+            std::env::temp_dir().join(format!("git-append-test-lock-{}", std::process::id()));
+        let _ = fs::remove_dir_all(&folder);
+        let git_folder = folder.to_string_lossy().into_owned();
+
+        assert!(repository_lock_path(&git_folder, false).is_err());
+        assert!(!folder.exists());
+        assert_eq!(
+            Ok(folder.join("git-append.lock")),
+            repository_lock_path(&git_folder, true)
+        );
+        assert!(folder.is_dir());
+        fs::create_dir(folder.join(".git")).unwrap();
+        assert_eq!(
+            Ok(folder.join(".git").join("git-append.lock")),
+            repository_lock_path(&git_folder, false)
+        );
+
+        fs::remove_dir_all(&folder).unwrap();
+    }
+}
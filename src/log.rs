@@ -0,0 +1,25 @@
+use std::cell::RefCell;
+
+thread_local! {
+    static PREFIX: RefCell<Option<String>> = const { RefCell::new(None) };
+}
+
+/// Runs `f` with every `log!` line of this thread prefixed by `[name]`, appenders running concurrently can then be told apart.
+pub fn with_prefix<T>(name: &str, f: impl FnOnce() -> T) -> T {
+    PREFIX.with(|prefix| *prefix.borrow_mut() = Some(format!("[{}] ", name)));
+    let result = f();
+    PREFIX.with(|prefix| *prefix.borrow_mut() = None);
+    result
+}
+
+pub fn prefix() -> String {
+    PREFIX.with(|prefix| prefix.borrow().clone().unwrap_or_default())
+}
+
+/// Like `eprintln!`, with the prefix of the current appender.
+#[macro_export]
+macro_rules! log {
+    ($($arg:tt)*) => {
+        eprintln!("{}{}", $crate::log::prefix(), format!($($arg)*))
+    };
+}
@@ -1,21 +1,46 @@
 use crate::{
     appender::append,
-    core::{decrypt_file, process_file},
-    file::{get_file_contents_as_lines, get_file_contents_strip_final_end_line, parse_config},
-    git::{open, pull},
+    config_file::{add_link, check_editable, read_document, remove_link, write_document},
+    core::{
+        apply_network, cleanup_refspecs, decrypt_file, folder_files, init, process_file, prompt,
+        reconcile, remote_options,
+    },
+    file::{expand_path, get_file_contents_as_lines, parse_config, read_config_file},
+    git::{open, open_or_init_bare, pull},
+};
+use clap::{Args, Parser, Subcommand};
+use config::{Feature, GitAppender, GitConfig, GitLink, Network};
+use git::{
+    check_staged_changes, commit, describe_error, pending_commits, push_mirror, push_pending,
+    signature, CommitFile,
+};
+use lock::{file_lock_path, repository_lock_path, Lock, LockError, LockOptions, OnLocked};
+use message::commit_message;
+use report::{
+    AppenderReport, LinkReport, MirrorReport, RunReport, RunStatus, EXIT_PARTIAL_FAILURE,
 };
-use clap::{Parser, Subcommand};
-use config::GitConfig;
-use git::{commit_and_push, signature};
-use glob::glob;
-use std::collections::HashSet;
+use signing::signer;
+use std::collections::{HashMap, HashSet};
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+use std::thread;
+use std::time::{Duration, Instant};
 mod age;
 mod appender;
 mod config;
+mod config_file;
 mod core;
 mod encryption;
 mod file;
 mod git;
+mod lock;
+mod log;
+mod message;
+mod report;
+mod signing;
+mod status;
+mod validate;
 
 fn main() {
     let args = Cli::parse();
@@ -23,15 +48,124 @@ fn main() {
         Commands::Run {
             config_path,
             include_appender,
-        } => main_run(config_path, include_appender),
+            jobs,
+            on_locked,
+            lock_timeout,
+            report,
+            report_file,
+        } => main_run(
+            config_path,
+            include_appender,
+            jobs,
+            LockOptions {
+                on_locked,
+                timeout: Duration::from_secs(lock_timeout),
+            },
+            report.map(|format| ReportArgs {
+                format,
+                report_file,
+            }),
+        ),
         Commands::Cat {
             config_path,
             file,
             repository_location,
         } => decrypt_file(config_path, repository_location, file),
+        Commands::Init {
+            config_path,
+            url,
+            repository_location,
+            username,
+            token_file,
+            certificate_sha256,
+            link,
+            link_args,
+            interactive,
+        } => {
+            let link = link
+                .or_else(|| {
+                    interactive
+                        .then(|| prompt("Local file to link (empty for none)"))
+                        .flatten()
+                })
+                .map(|local_path| link_args.into_link(local_path, interactive));
+            let git_config = username
+                .zip(token_file)
+                .map(|(username, token_file)| GitConfig {
+                    username,
+                    token_file,
+                });
+            init(
+                config_path,
+                url,
+                repository_location,
+                git_config,
+                certificate_sha256,
+                link,
+            )
+            .unwrap_or_else(|e| exit_with_error(&e))
+        }
+        Commands::Link { command } => match command {
+            LinkCommands::Add {
+                config_path,
+                repository_location,
+                local_path,
+                link_args,
+            } => {
+                let (folder, local_path, link) = link_args.into_link(local_path, false);
+                add_and_sync_link(
+                    &config_path,
+                    &repository_location,
+                    folder,
+                    &local_path,
+                    &link,
+                )
+                .unwrap_or_else(|e| exit_with_error(&e));
+            }
+            LinkCommands::Remove {
+                config_path,
+                repository_location,
+                local_path,
+                folder,
+            } => edit_config(&config_path, |document| {
+                remove_link(document, &repository_location, folder, &local_path)
+            })
+            .unwrap_or_else(|e| exit_with_error(&e)),
+        },
+        Commands::Status { config_path, json } => {
+            let statuses = status::status(parse_config(config_path));
+            if json {
+                println!("{}", serde_json::to_string_pretty(&statuses).unwrap());
+            } else {
+                status::print_status(&statuses);
+            }
+        }
+        Commands::Validate { config_path } => {
+            let diagnostics = validate::validate(config_path);
+            for diagnostic in diagnostics.iter() {
+                eprintln!("{}", diagnostic);
+            }
+            if diagnostics.is_empty() {
+                println!("Config is valid");
+            } else {
+                std::process::exit(1);
+            }
+        }
+        Commands::CleanupRefspecs { config_path } => cleanup_refspecs(config_path),
+        Commands::Schema => {
+            println!(
+                "{}",
+                serde_json::to_string_pretty(&config::schema()).unwrap()
+            );
+        }
+        Commands::ShowConfig { config_path } => {
+            let mut config = serde_json::to_value(parse_config(config_path)).unwrap();
+            config.sort_all_objects();
+            println!("{}", serde_json::to_string_pretty(&config).unwrap());
+        }
         Commands::CatAppend { file_one, file_two } => {
-            let file_one_content = get_file_contents_as_lines(&file_one).unwrap_or(Vec::new());
-            let file_two_content = get_file_contents_as_lines(&file_two).unwrap_or(Vec::new());
+            let (file_one_content, _) = get_file_contents_as_lines(&file_one).unwrap_or_default();
+            let (file_two_content, _) = get_file_contents_as_lines(&file_two).unwrap_or_default();
 
             let (local, remote) = append(
                 file_one_content,
@@ -46,8 +180,18 @@ fn main() {
     }
 }
 
-fn main_run(path: String, maybe_include_appender: Option<String>) {
+/// Appenders are run on up to `jobs` threads, the links of one appender are always processed in sequence.
+fn main_run(
+    path: String,
+    maybe_include_appender: Option<String>,
+    jobs: usize,
+    lock_options: LockOptions,
+    report: Option<ReportArgs>,
+) {
+    let start = Instant::now();
     let configs = parse_config(path);
+    // without its network settings, no appender is run
+    let network_error = apply_network(configs.network.as_ref()).err();
 
     let appenders = maybe_include_appender
         .map(|include_appender| {
@@ -60,82 +204,367 @@ fn main_run(path: String, maybe_include_appender: Option<String>) {
         })
         .unwrap_or(configs.appenders);
 
-    for (git_folder, appender) in appenders.iter() {
-        let mut files = Vec::new();
-        let repo = open(&format!("{}/.git", git_folder));
-        // let c = repo.config().unwrap();
-        // c.entries(None)
-        //     .unwrap()
-        //     .for_each(|d| log::debug!("{:?}:{:?}", d.name(), d.value()))
-        //     .unwrap();
-        let credentials = appender.git_config.clone().map(
-            |GitConfig {
-                 username,
-                 token_file,
-             }| {
-                (
-                    username,
-                    String::from_utf8(
-                        get_file_contents_strip_final_end_line(&token_file)
-                            .expect(&format!("Could not find {}", token_file)),
-                    )
-                    .expect(&format!("Error converting from utf8")),
-                )
-            },
+    let queue = Mutex::new(appenders.iter());
+    let reports = Mutex::new(Vec::new());
+    thread::scope(|scope| {
+        for _ in 0..jobs.clamp(1, appenders.len().max(1)) {
+            scope.spawn(|| loop {
+                let next = queue.lock().unwrap().next();
+                let Some((git_folder, appender)) = next else {
+                    break;
+                };
+                let appender_report = match &network_error {
+                    Some(e) => AppenderReport {
+                        repository: git_folder.clone(),
+                        errors: vec![e.clone()],
+                        ..AppenderReport::default()
+                    },
+                    None => log::with_prefix(git_folder, || {
+                        run_appender(
+                            git_folder,
+                            appender,
+                            configs.network.as_ref(),
+                            &lock_options,
+                        )
+                    }),
+                };
+                print_errors(&appender_report);
+                reports.lock().unwrap().push(appender_report);
+            });
+        }
+    });
+    let mut reports = reports.into_inner().unwrap();
+    reports.sort_by(|a, b| a.repository.cmp(&b.repository));
+    let run_report = RunReport::new(reports, start.elapsed().as_millis());
+
+    if let Some(ReportArgs {
+        format,
+        report_file,
+    }) = report
+    {
+        let content = match format {
+            ReportFormat::Json => serde_json::to_string_pretty(&run_report).unwrap(),
+        };
+        match report_file {
+            Some(report_file) => fs::write(&report_file, content + "\n")
+                .unwrap_or_else(|e| panic!("Could not write {}: {}", report_file, e)),
+            None => println!("{}", content),
+        }
+        std::process::exit(run_report.exit_code());
+    }
+    if run_report.status == RunStatus::PartialFailure {
+        std::process::exit(EXIT_PARTIAL_FAILURE);
+    }
+}
+
+fn exit_with_error(error: &str) -> ! {
+    eprintln!("{}", error);
+    std::process::exit(1);
+}
+
+/// YAML and TOML configs with comments are not edited, see `check_editable`.
+fn edit_config(
+    config_path: &String,
+    edit: impl FnOnce(&mut serde_json::Value) -> Result<(), String>,
+) -> Result<(), String> {
+    let path = Path::new(config_path);
+    check_editable(path)?;
+    let mut document = read_document(path)?;
+    edit(&mut document)?;
+    write_document(path, &document)?;
+    println!("Updated {}", config_path);
+    Ok(())
+}
+
+/// Adds a link to the config, once its appender is known to be usable, then runs the appender with only this link
+/// for a first merge.
+fn add_and_sync_link(
+    config_path: &String,
+    repository_location: &str,
+    folder: bool,
+    local_path: &str,
+    link: &GitLink,
+) -> Result<(), String> {
+    let configs = read_config_file(config_path)?;
+    let (git_folder, appender) = configs.find_appender(repository_location)?;
+    if appender.bare != Some(true) {
+        open(git_folder)?;
+    }
+    edit_config(config_path, |document| {
+        add_link(document, repository_location, folder, local_path, link)
+    })?;
+
+    let configs = read_config_file(config_path)?;
+    apply_network(configs.network.as_ref())?;
+    let (git_folder, appender) = configs.find_appender(repository_location)?;
+    let local_path = expand_path(local_path)?;
+    let only_link = |links: &HashMap<String, GitLink>, keep: bool| {
+        links
+            .iter()
+            .filter(|(path, _)| keep && **path == local_path)
+            .map(|(path, link)| (path.clone(), link.clone()))
+            .collect()
+    };
+    let report = run_appender(
+        git_folder,
+        &GitAppender {
+            links: only_link(&appender.links, !folder),
+            folder_links: only_link(&appender.folder_links, folder),
+            ..appender.clone()
+        },
+        configs.network.as_ref(),
+        &LockOptions::default(),
+    );
+    print_errors(&report);
+    if RunReport::new(vec![report], 0).status == RunStatus::PartialFailure {
+        std::process::exit(EXIT_PARTIAL_FAILURE);
+    }
+    Ok(())
+}
+
+fn print_errors(report: &AppenderReport) {
+    for error in report
+        .errors
+        .iter()
+        .chain(report.links.iter().filter_map(|l| l.error.as_ref()))
+    {
+        eprintln!("Error in {}: {}", report.repository, error);
+    }
+    if report.offline {
+        eprintln!(
+            "Warning in {}: the remote is unreachable, {} commit(s) pending",
+            report.repository, report.pending
         );
-        pull(&repo, credentials.clone(), "master".to_owned());
-
-        for (file_path, file_appender) in appender.links.iter() {
-            let new_files = process_file(
-                file_appender,
-                file_path,
-                file_appender.source_path.to_owned(),
-                git_folder,
-                &repo,
+    }
+    for mirror in report.mirrors.iter() {
+        if let Some(error) = &mirror.error {
+            eprintln!(
+                "Warning in {}: mirror {}: {}",
+                report.repository, mirror.name, error
             );
+        }
+    }
+}
 
-            files.extend(new_files);
-        }
-        for (file_path, folder_appender) in appender.folder_links.iter() {
-            for entry in glob(&format!("{}/**/*", file_path)).expect("Failed to read glob pattern")
-            {
-                let new_files = match entry {
-                    Ok(path) => {
-                        if path.is_file() && !path.to_str().unwrap().contains(".git") {
-                            let local_path = path.strip_prefix(file_path).unwrap();
-
-                            process_file(
-                                folder_appender,
-                                &format!("{}", path.display()),
-                                format!(
-                                    "{}/{}",
-                                    folder_appender.source_path.to_owned(),
-                                    local_path.display(),
-                                ),
-                                git_folder,
-                                &repo,
-                            )
-                        } else {
-                            println!("Ignored folder or link: {:?} (or in .git folder)", path);
-                            Vec::new()
-                        }
-                    }
-                    Err(e) => {
-                        println!("Ignored: {:?}", e);
-                        Vec::new()
-                    }
-                };
+/// Takes a lock, as a skip or an error message if it is held by another run.
+fn lock(path: Result<PathBuf, LockError>, options: &LockOptions) -> Result<Lock, LockError> {
+    let lock = path.and_then(|path| Lock::acquire(&path, options));
+    if let Err(e) = &lock {
+        log!("{}", e);
+    }
+    lock
+}
+
+/// Pulls, merges every link of the appender and pushes the changes.
+/// Errors are collected in the report instead of stopping the run.
+/// The repository and every local file are locked while processed, so overlapping runs do not interleave.
+fn run_appender(
+    git_folder: &String,
+    appender: &GitAppender,
+    network: Option<&Network>,
+    lock_options: &LockOptions,
+) -> AppenderReport {
+    let start = Instant::now();
+    let mut report = AppenderReport {
+        repository: git_folder.clone(),
+        ..AppenderReport::default()
+    };
+    let _repository_lock = match lock(
+        repository_lock_path(git_folder, appender.bare == Some(true)),
+        lock_options,
+    ) {
+        Ok(lock) => lock,
+        Err(e) => {
+            match e {
+                LockError::Skipped(_) => report.skipped = Some(e.to_string()),
+                _ => report.errors.push(e.to_string()),
+            }
+            report.duration_ms = start.elapsed().as_millis();
+            return report;
+        }
+    };
+    let pull_policy = appender.pull_policy.clone().unwrap_or_default();
+    let merge_queued =
+        |path: &str, local: &[u8], remote: &[u8]| reconcile(appender, path, local, remote);
+    let opened = remote_options(
+        appender.git_config.clone(),
+        appender.certificate_sha256.clone(),
+        network,
+    )
+    .and_then(|options| {
+        let repo = match appender.bare {
+            Some(true) => open_or_init_bare(git_folder, appender.url.as_deref()),
+            _ => open(git_folder),
+        }?;
+        let sign = signature(&repo, appender.author.as_ref())?;
+        let signer = signer(&repo, appender.signing.as_ref())?;
+        let synced = repo
+            .head()
+            .and_then(|head| head.peel_to_commit())
+            .map(|commit| commit.id())
+            .ok();
+        let online = pull(
+            &repo,
+            &options,
+            "master".to_owned(),
+            &pull_policy,
+            &sign,
+            signer.as_ref(),
+            &merge_queued,
+        )?;
+        Ok((repo, options, sign, signer, online, synced))
+    });
+    let (repo, options, sign, signer, mut online, synced) = match opened {
+        Ok(opened) => opened,
+        Err(e) => {
+            report.errors.push(e);
+            report.duration_ms = start.elapsed().as_millis();
+            return report;
+        }
+    };
+    // checked before any file is written, the staged changes would be committed with the linked files
+    if !appender.allow_staged_changes.unwrap_or(false) {
+        let linked = |path: &str| {
+            appender.links.values().any(|link| link.source_path == path)
+                || appender
+                    .folder_links
+                    .values()
+                    .any(|link| path.starts_with(&format!("{}/", link.source_path)))
+        };
+        if let Err(e) = check_staged_changes(&repo, linked) {
+            report.errors.push(e);
+            report.duration_ms = start.elapsed().as_millis();
+            return report;
+        }
+    }
 
-                files.extend(new_files);
+    let process = |file_appender: &GitLink, local_path: &String, repo_file_path: String| {
+        let _file_lock = match lock(file_lock_path(local_path), lock_options) {
+            Ok(lock) => lock,
+            Err(e) => {
+                let (error, skipped) = match e {
+                    LockError::Skipped(_) => (None, Some(e.to_string())),
+                    _ => (Some(e.to_string()), None),
+                };
+                return LinkReport {
+                    local_path: local_path.clone(),
+                    source_path: repo_file_path,
+                    error,
+                    skipped,
+                    ..LinkReport::default()
+                };
+            }
+        };
+        process_file(
+            file_appender,
+            local_path,
+            repo_file_path.clone(),
+            git_folder,
+            &repo,
+            synced,
+        )
+        .unwrap_or_else(|e| LinkReport {
+            local_path: local_path.clone(),
+            source_path: repo_file_path.clone(),
+            error: Some(e),
+            ..LinkReport::default()
+        })
+    };
+    for (file_path, file_appender) in appender.links.iter() {
+        report.links.push(process(
+            file_appender,
+            file_path,
+            file_appender.source_path.to_owned(),
+        ));
+    }
+    for (file_path, folder_appender) in appender.folder_links.iter() {
+        match folder_files(file_path, folder_appender) {
+            Ok(files) => {
+                for (local_path, repo_file_path) in files {
+                    report
+                        .links
+                        .push(process(folder_appender, &local_path, repo_file_path));
+                }
             }
+            Err(e) => report.links.push(LinkReport {
+                local_path: file_path.clone(),
+                source_path: folder_appender.source_path.clone(),
+                error: Some(e),
+                ..LinkReport::default()
+            }),
         }
+    }
 
-        if !files.is_empty() {
-            let sign = signature();
-            commit_and_push(&repo, credentials.clone(), &sign);
-            pull(&repo, credentials.clone(), "master".to_owned());
+    let changed: Vec<&LinkReport> = report.links.iter().filter(|l| l.remote_changed).collect();
+    let files: Vec<CommitFile> = changed.iter().map(|l| (&l.source_path, l.blob)).collect();
+    if !files.is_empty() {
+        let committed = commit(
+            &repo,
+            &sign,
+            &commit_message(appender.commit_message.as_deref(), git_folder, &changed),
+            &files,
+            signer.as_ref(),
+        );
+        match committed {
+            Ok(oid) => report.commit = oid.map(|oid| oid.to_string()),
+            Err(e) => report.errors.push(e),
+        }
+    }
+    // also pushes the commits queued by previous runs
+    let pending = pending_commits(&repo, "master").unwrap_or_else(|e| {
+        report.errors.push(e);
+        0
+    });
+    if online && report.errors.is_empty() {
+        let pushed = push_pending(
+            &repo,
+            &options,
+            &pull_policy,
+            &sign,
+            signer.as_ref(),
+            &merge_queued,
+        );
+        match pushed {
+            Ok(pushed) => {
+                online = pushed;
+                report.pushed = if pushed { pending } else { 0 };
+            }
+            Err(e) => report.errors.push(e),
+        }
+    }
+    report.offline = !online;
+    report.pending = pending_commits(&repo, "master").unwrap_or_else(|e| {
+        report.errors.push(e);
+        0
+    });
+    // queued commits may still be merged with the remote, a mirror would not fast-forward to them afterwards
+    if !online && !appender.mirrors.is_empty() {
+        log!("Offline, the mirrors are pushed on the next run reaching the remote");
+    }
+    if online && report.errors.is_empty() {
+        let mut mirrors: Vec<_> = appender.mirrors.iter().collect();
+        mirrors.sort_by_key(|(name, _)| *name);
+        for (name, mirror) in mirrors {
+            let pushed = remote_options(
+                mirror.git_config.clone(),
+                mirror.certificate_sha256.clone(),
+                network,
+            )
+            .and_then(|options| {
+                push_mirror(&repo, &mirror.url, &options).map_err(|e| describe_error(&e))
+            });
+            if let Err(e) = &pushed {
+                log!("Could not push to mirror {}: {}", name, e);
+            }
+            report.mirrors.push(MirrorReport {
+                name: name.clone(),
+                pushed: pushed.is_ok(),
+                error: pushed.err(),
+            });
         }
     }
+    report.duration_ms = start.elapsed().as_millis();
+    report
 }
 
 #[derive(Parser, Debug)]
@@ -155,6 +584,27 @@ enum Commands {
 
         #[arg(long)]
         include_appender: Option<String>,
+
+        /// Number of appenders processed at the same time.
+        #[arg(short, long, default_value_t = 4)]
+        jobs: usize,
+
+        /// What to do when a repository or a local file is locked by another run.
+        #[arg(long, value_enum, default_value_t = OnLocked::Wait)]
+        on_locked: OnLocked,
+
+        /// Seconds to wait for a lock before failing, with `--on-locked=wait`.
+        #[arg(long, default_value_t = 60)]
+        lock_timeout: u64,
+
+        /// Output a report of the run, and exit with 0 (no changes), 10 (synced) or 20 (partial failure).
+        /// Without it, the exit code is 20 on a partial failure and 0 otherwise.
+        #[arg(long, value_enum)]
+        report: Option<ReportFormat>,
+
+        /// Write the report to this file instead of stdout.
+        #[arg(long, requires = "report")]
+        report_file: Option<String>,
     },
     /// Read a file as the run command would read it, to see what it contains, from your config file.
     #[command(arg_required_else_help = true)]
@@ -172,6 +622,90 @@ enum Commands {
         file: String,
     },
 
+    /// Clone a repository (initializing it if empty) and add it to the config, with an optional first link.
+    #[command(arg_required_else_help = true)]
+    Init {
+        /// Configuration file location, created if missing (see `tests/example-config.json`).
+        #[arg(short, long)]
+        config_path: String,
+
+        /// HTTP URL of the repository, added as `http-origin`.
+        #[arg(long)]
+        url: String,
+
+        /// Where to clone the repository.
+        #[arg(short, long)]
+        repository_location: String,
+
+        /// Username for the HTTP remote.
+        #[arg(long, requires = "token_file")]
+        username: Option<String>,
+
+        /// File containing the token for the HTTP remote.
+        #[arg(long, requires = "username")]
+        token_file: Option<String>,
+
+        /// SHA-256 fingerprint the certificate of the remote must have, written to the config.
+        #[arg(long)]
+        certificate_sha256: Option<String>,
+
+        /// Local file (or folder with `--folder`) to link.
+        #[arg(long)]
+        link: Option<String>,
+
+        #[command(flatten)]
+        link_args: LinkArgs,
+
+        /// Ask for the link values that are not given as flags.
+        #[arg(short, long)]
+        interactive: bool,
+    },
+
+    /// Add or remove a link in the config file.
+    Link {
+        #[command(subcommand)]
+        command: LinkCommands,
+    },
+
+    /// Show, for every link, how it differs from the last fetched remote state (no network access).
+    #[command(arg_required_else_help = true)]
+    Status {
+        /// Configuration file location (see `tests/example-config.json`).
+        #[arg(short, long)]
+        config_path: String,
+
+        /// Output as JSON.
+        #[arg(long)]
+        json: bool,
+    },
+
+    /// Check the config (format, regexes, repositories, password and token files) without running anything.
+    #[command(arg_required_else_help = true)]
+    Validate {
+        /// Configuration file location (see `tests/example-config.json`).
+        #[arg(short, long)]
+        config_path: String,
+    },
+
+    /// Remove the `refs/heads/X:refs/heads/X` fetch refspecs that older versions added to the repositories on every run.
+    #[command(arg_required_else_help = true)]
+    CleanupRefspecs {
+        /// Configuration file location (see `tests/example-config.json`).
+        #[arg(short, long)]
+        config_path: String,
+    },
+
+    /// Output the JSON Schema of the config file (see `tests/config-schema.json`).
+    Schema,
+
+    /// Output the config as it is used by the run command, after includes, host overlay and defaults are merged.
+    #[command(arg_required_else_help = true)]
+    ShowConfig {
+        /// Configuration file location (see `tests/example-config.json`).
+        #[arg(short, long)]
+        config_path: String,
+    },
+
     /// Output the result of the append merge between two files.
     #[command(arg_required_else_help = true)]
     CatAppend {
@@ -184,3 +718,298 @@ enum Commands {
         file_two: String,
     },
 }
+
+#[derive(Debug, Subcommand)]
+enum LinkCommands {
+    /// Add a link to an appender of the config, and merge it right away.
+    #[command(arg_required_else_help = true)]
+    Add {
+        /// Configuration file location (see `tests/example-config.json`).
+        #[arg(short, long)]
+        config_path: String,
+
+        /// Repository location, as written in the config.
+        #[arg(short, long)]
+        repository_location: String,
+
+        /// Local file (or folder with `--folder`) to link.
+        #[arg(short, long)]
+        local_path: String,
+
+        #[command(flatten)]
+        link_args: LinkArgs,
+    },
+    /// Remove a link from an appender of the config (no file is deleted).
+    #[command(arg_required_else_help = true)]
+    Remove {
+        /// Configuration file location (see `tests/example-config.json`).
+        #[arg(short, long)]
+        config_path: String,
+
+        /// Repository location, as written in the config.
+        #[arg(short, long)]
+        repository_location: String,
+
+        /// Local file (or folder with `--folder`), as written in the config.
+        #[arg(short, long)]
+        local_path: String,
+
+        /// Remove from `folder_links` instead of `links`.
+        #[arg(long)]
+        folder: bool,
+    },
+}
+
+#[derive(Debug, Clone, Copy, clap::ValueEnum)]
+enum ReportFormat {
+    Json,
+}
+
+struct ReportArgs {
+    format: ReportFormat,
+    report_file: Option<String>,
+}
+
+#[derive(Debug, Args)]
+struct LinkArgs {
+    /// Path in the repository (defaults to the local file name).
+    #[arg(long)]
+    source_path: Option<String>,
+
+    /// File containing the passphrase used to encrypt the file in the repository.
+    #[arg(long)]
+    password_file: Option<String>,
+
+    /// Feature to enable on the link, can be repeated.
+    #[arg(long, value_enum)]
+    feature: Vec<Feature>,
+
+    /// Link a whole folder (`folder_links`) instead of a file.
+    #[arg(long)]
+    folder: bool,
+}
+
+impl LinkArgs {
+    fn into_link(self, local_path: String, interactive: bool) -> (bool, String, GitLink) {
+        let default_source_path = Path::new(&local_path)
+            .file_name()
+            .map(|name| name.to_string_lossy().into_owned())
+            .unwrap_or_default();
+        let source_path = self
+            .source_path
+            .or_else(|| {
+                interactive
+                    .then(|| {
+                        prompt(&format!(
+                            "Path in the repository (default: {})",
+                            default_source_path
+                        ))
+                    })
+                    .flatten()
+            })
+            .unwrap_or(default_source_path);
+        let password_file = self.password_file.or_else(|| {
+            interactive
+                .then(|| prompt("Password file to encrypt it (empty for none)"))
+                .flatten()
+        });
+        let link = GitLink {
+            source_path,
+            source_branch: None,
+            password_file,
+            remove_lines: None,
+            exclude_patterns: None,
+            features: Some(self.feature.into_iter().collect())
+                .filter(|f: &HashSet<_>| !f.is_empty()),
+            mode: None,
+            template: None,
+            unset: None,
+        };
+        (self.folder, local_path, link)
+    }
+}
+
+#[cfg(test)]
+pub mod tests {
+    use pretty_assertions::assert_eq;
+    use std::fs;
+
+    use git2::Repository;
+    use serde_json::json;
+
+    use crate::appender::read_lines;
+    use crate::config::{GitAppender, Network};
+    use crate::git::tests::Fixture;
+    use crate::lock::LockOptions;
+    use crate::report::{MirrorReport, RunReport, RunStatus, EXIT_PARTIAL_FAILURE};
+    use crate::run_appender;
+
+    #[test]
+    fn test_missing_token_file() {
+        let fixture = Fixture::new();
+        let path = |name: &str| fixture.path(name);
+        fixture.clone("repo");
+        fs::write(path("history"), "a\n").unwrap();
+        let appender: GitAppender = serde_json::from_value(json!({
+            "git_config": { "username": "someone", "token_file": path("missing-token") },
+            "links": { path("history"): { "source_path": "history" } }
+        }))
+        .unwrap();
+
+        let report = run_appender(&path("repo"), &appender, None, &LockOptions::default());
+        assert_eq!(1, report.errors.len());
+        assert!(report.errors[0].contains("missing-token"));
+        assert!(report.links.is_empty());
+        let run_report = RunReport::new(vec![report], 0);
+        assert_eq!(RunStatus::PartialFailure, run_report.status);
+        assert_eq!(EXIT_PARTIAL_FAILURE, run_report.exit_code());
+
+        // only a warning on a mirror
+        let appender: GitAppender = serde_json::from_value(json!({
+            "links": { path("history"): { "source_path": "history" } },
+            "mirrors": { "backup": {
+                "url": path("remote.git"),
+                "git_config": { "username": "someone", "token_file": path("missing-token") }
+            } }
+        }))
+        .unwrap();
+        let report = run_appender(&path("repo"), &appender, None, &LockOptions::default());
+        assert_eq!(Vec::<String>::new(), report.errors);
+        assert!(report.mirrors[0]
+            .error
+            .as_ref()
+            .is_some_and(|e| e.contains("missing-token")));
+    }
+
+    #[test]
+    fn test_encrypted_queued_commit() {
+        let fixture = Fixture::new();
+        let path = |name: &str| fixture.path(name);
+        let repo = fixture.clone("repo");
+        fs::write(path("password"), "secret").unwrap();
+        let appender = |local: &str| -> GitAppender {
+            serde_json::from_value(json!({
+                "links": { path(local): { "source_path": "history", "password_file": path("password") } }
+            }))
+            .unwrap()
+        };
+        let network: Network = serde_json::from_value(json!({ "retries": 0 })).unwrap();
+        let run = |git_folder: &str, local: &str| {
+            let report = run_appender(
+                &path(git_folder),
+                &appender(local),
+                Some(&network),
+                &LockOptions::default(),
+            );
+            assert_eq!(Vec::<String>::new(), report.errors);
+            report
+        };
+        fs::write(path("history"), "a\n").unwrap();
+        run("repo", "history");
+
+        // nothing listens on port 1
+        repo.remote_set_url("http-origin", "http://127.0.0.1:1/remote.git")
+            .unwrap();
+        fs::write(path("history"), "a\noffline\n").unwrap();
+        assert_eq!(1, run("repo", "history").pending);
+        // the line only remains in the queued commit
+        fs::write(path("history"), "a\n").unwrap();
+
+        fixture.clone("upstream");
+        fs::write(path("upstream-history"), "remote\n").unwrap();
+        run("upstream", "upstream-history");
+
+        repo.remote_set_url("http-origin", &path("remote.git"))
+            .unwrap();
+        let report = run("repo", "history");
+        assert!(!report.offline);
+        assert_eq!(0, report.pending);
+        let remote = Repository::open_bare(path("remote.git")).unwrap();
+        let blob = remote
+            .revparse_single("master:history")
+            .and_then(|object| object.peel_to_blob())
+            .unwrap();
+        let link = &appender("history").links[&path("history")];
+        let (lines, _) = read_lines(link, blob.content().to_vec()).unwrap();
+        assert_eq!(
+            vec![b"a".to_vec(), b"offline".to_vec(), b"remote".to_vec()],
+            lines
+        );
+        assert_eq!(
+            "a\noffline\nremote\n",
+            fs::read_to_string(path("history")).unwrap()
+        );
+    }
+
+    #[test]
+    fn test_mirrors() {
+        let fixture = Fixture::new();
+        let path = |name: &str| fixture.path(name);
+        let mirror = Repository::init_bare(path("mirror.git")).unwrap();
+        fixture.clone("repo");
+        fs::write(path("history"), "a\n").unwrap();
+        let appender: GitAppender = serde_json::from_value(json!({
+            "links": { path("history"): { "source_path": "history" } },
+            "mirrors": {
+                "backup": { "url": path("mirror.git") },
+                // nothing listens on port 1
+                "offline": { "url": "http://127.0.0.1:1/mirror.git" }
+            }
+        }))
+        .unwrap();
+        let network: Network = serde_json::from_value(json!({ "retries": 0 })).unwrap();
+
+        let report = run_appender(
+            &path("repo"),
+            &appender,
+            Some(&network),
+            &LockOptions::default(),
+        );
+        assert_eq!(Vec::<String>::new(), report.errors);
+        let mirrors: Vec<(&str, bool)> = report
+            .mirrors
+            .iter()
+            .map(|MirrorReport { name, pushed, .. }| (name.as_str(), *pushed))
+            .collect();
+        assert_eq!(vec![("backup", true), ("offline", false)], mirrors);
+        assert!(report.mirrors[1].error.is_some());
+        let repo = Repository::open(path("repo")).unwrap();
+        assert_eq!(
+            repo.refname_to_id("refs/heads/master").ok(),
+            mirror.refname_to_id("refs/heads/master").ok()
+        );
+        // a failing mirror is only a warning
+        assert_eq!(RunStatus::Synced, RunReport::new(vec![report], 0).status);
+    }
+
+    #[test]
+    fn test_mirrors_offline() {
+        let fixture = Fixture::new();
+        let path = |name: &str| fixture.path(name);
+        let mirror = Repository::init_bare(path("mirror.git")).unwrap();
+        let repo = fixture.clone("repo");
+        // nothing listens on port 1
+        repo.remote_set_url("http-origin", "http://127.0.0.1:1/remote.git")
+            .unwrap();
+        fs::write(path("history"), "a\n").unwrap();
+        let appender: GitAppender = serde_json::from_value(json!({
+            "links": { path("history"): { "source_path": "history" } },
+            "mirrors": { "backup": { "url": path("mirror.git") } }
+        }))
+        .unwrap();
+        let network: Network = serde_json::from_value(json!({ "retries": 0 })).unwrap();
+
+        let report = run_appender(
+            &path("repo"),
+            &appender,
+            Some(&network),
+            &LockOptions::default(),
+        );
+        assert_eq!(Vec::<String>::new(), report.errors);
+        assert!(report.offline);
+        assert_eq!(1, report.pending);
+        // the queued commit is not pushed to the mirror
+        assert!(report.mirrors.is_empty());
+        assert!(mirror.refname_to_id("refs/heads/master").is_err());
+    }
+}
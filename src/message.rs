@@ -0,0 +1,184 @@
+use std::env;
+
+use time::{format_description::well_known::Rfc3339, OffsetDateTime};
+
+use crate::config::hostname;
+use crate::report::LinkReport;
+
+/// Used when the appender has no `commit_message`.
+pub const DEFAULT_TEMPLATE: &str = "chore(append): {paths} from {hostname}\n\n{changes}";
+
+/// Paths listed by `{paths}`, the other ones are only counted so the subject line stays short.
+const MAX_PATHS: usize = 3;
+
+/// Builds the commit message from the template of the appender, and adds the trailers.
+/// Placeholders: `{hostname}`, `{user}`, `{appender}`, `{paths}` (at most `MAX_PATHS`), `{changes}` (one `path: +lines` per line)
+/// and `{timestamp}`.
+pub fn commit_message(template: Option<&str>, appender: &str, links: &[&LinkReport]) -> String {
+    let values = Values {
+        hostname: hostname(),
+        user: env::var("USER")
+            .or(env::var("LOGNAME"))
+            .unwrap_or("unknown".to_owned()),
+        timestamp: OffsetDateTime::now_utc()
+            .replace_nanosecond(0)
+            .unwrap()
+            .format(&Rfc3339)
+            .unwrap(),
+    };
+    render(
+        template.unwrap_or(DEFAULT_TEMPLATE),
+        appender,
+        links,
+        &values,
+    )
+}
+
+struct Values {
+    hostname: String,
+    user: String,
+    timestamp: String,
+}
+
+fn render(template: &str, appender: &str, links: &[&LinkReport], values: &Values) -> String {
+    let value = |placeholder: &str| match placeholder {
+        "hostname" => Some(values.hostname.clone()),
+        "user" => Some(values.user.clone()),
+        "appender" => Some(appender.to_owned()),
+        "paths" => Some(summarize_paths(links)),
+        "changes" => Some(
+            links
+                .iter()
+                .map(|l| format!("{}: +{}", l.source_path, l.lines_added_remotely))
+                .collect::<Vec<_>>()
+                .join("\n"),
+        ),
+        "timestamp" => Some(values.timestamp.clone()),
+        _ => None,
+    };
+    // in one pass, a value containing a placeholder is kept as it is
+    let mut message = String::new();
+    let mut rest = template;
+    while let Some(start) = rest.find('{') {
+        message.push_str(&rest[..start]);
+        rest = &rest[start..];
+        let replaced = rest
+            .find('}')
+            .and_then(|end| Some((value(&rest[1..end])?, end + 1)));
+        match replaced {
+            Some((value, length)) => {
+                message.push_str(&value);
+                rest = &rest[length..];
+            }
+            None => {
+                message.push('{');
+                rest = &rest[1..];
+            }
+        }
+    }
+    message.push_str(rest);
+
+    // git trailers, parsed by `git interpret-trailers`
+    let mut trailers = vec![
+        format!("Git-Append-Host: {}", values.hostname),
+        format!("Git-Append-User: {}", values.user),
+        format!("Git-Append-Appender: {}", appender),
+        format!("Git-Append-Timestamp: {}", values.timestamp),
+    ];
+    trailers.extend(links.iter().map(|l| {
+        format!(
+            "Git-Append-File: {} +{}",
+            l.source_path, l.lines_added_remotely
+        )
+    }));
+    format!("{}\n\n{}\n", message.trim_end(), trailers.join("\n"))
+}
+
+fn summarize_paths(links: &[&LinkReport]) -> String {
+    let paths: Vec<&str> = links.iter().map(|l| l.source_path.as_str()).collect();
+    if paths.len() <= MAX_PATHS {
+        paths.join(", ")
+    } else {
+        format!(
+            "{} and {} more",
+            paths[..MAX_PATHS].join(", "),
+            paths.len() - MAX_PATHS
+        )
+    }
+}
+
+/// Whether the message has the trailers added by `commit_message`.
+pub fn is_git_append_message(message: &str) -> bool {
+    message
+        .lines()
+        .any(|line| line.starts_with("Git-Append-Appender: "))
+}
+
+#[cfg(test)]
+pub mod tests {
+    use pretty_assertions::assert_eq;
+
+    use crate::message::{is_git_append_message, render, Values, DEFAULT_TEMPLATE};
+    use crate::report::LinkReport;
+
+    #[test]
+    fn test_render() {
+        let link = |source_path: &str, lines_added_remotely| LinkReport {
+            source_path: source_path.to_owned(),
+            lines_added_remotely,
+            ..LinkReport::default()
+        };
+        let values = Values {
+            hostname: "laptop".to_owned(),
+            user: "someone".to_owned(),
+            timestamp: "2024-01-01T00:00:00Z".to_owned(),
+        };
+        assert_eq!(
+            "chore(append): zsh_history, bash_history from laptop
+
+zsh_history: +3
+bash_history: +1
+
+Git-Append-Host: laptop
+Git-Append-User: someone
+Git-Append-Appender: /home/someone/history
+Git-Append-Timestamp: 2024-01-01T00:00:00Z
+Git-Append-File: zsh_history +3
+Git-Append-File: bash_history +1
+",
+            render(
+                DEFAULT_TEMPLATE,
+                "/home/someone/history",
+                &[&link("zsh_history", 3), &link("bash_history", 1)],
+                &values
+            )
+        );
+        assert_eq!(
+            "sync by someone at 2024-01-01T00:00:00Z
+
+Git-Append-Host: laptop
+Git-Append-User: someone
+Git-Append-Appender: /a
+Git-Append-Timestamp: 2024-01-01T00:00:00Z
+",
+            render("sync by {user} at {timestamp}", "/a", &[], &values)
+        );
+        assert_eq!(
+            "{user} {unknown} {paths
+
+Git-Append-Host: laptop
+Git-Append-User: someone
+Git-Append-Appender: {user}
+Git-Append-Timestamp: 2024-01-01T00:00:00Z
+",
+            render("{appender} {unknown} {paths", "{user}", &[], &values)
+        );
+        let links: Vec<LinkReport> = (1..=5).map(|i| link(&format!("f{}", i), 1)).collect();
+        let links: Vec<&LinkReport> = links.iter().collect();
+        assert!(render("{paths}", "/a", &links, &values).starts_with("f1, f2, f3 and 2 more\n"));
+        assert!(is_git_append_message(&render("sync", "/a", &[], &values)));
+        assert!(!is_git_append_message(
+            "Merge http-origin/master into master"
+        ));
+    }
+}
@@ -0,0 +1,138 @@
+use git2::{FileMode, Oid};
+use serde::Serialize;
+
+/// Exit codes used with `--report`, only `EXIT_PARTIAL_FAILURE` without it.
+pub const EXIT_NO_CHANGES: i32 = 0;
+pub const EXIT_SYNCED: i32 = 10;
+pub const EXIT_PARTIAL_FAILURE: i32 = 20;
+
+#[derive(Debug, Serialize, Clone, Copy, Eq, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum RunStatus {
+    NoChanges,
+    Synced,
+    PartialFailure,
+}
+
+#[derive(Debug, Serialize)]
+pub struct RunReport {
+    pub status: RunStatus,
+    pub duration_ms: u128,
+    pub appenders: Vec<AppenderReport>,
+}
+
+#[derive(Debug, Serialize, Default)]
+pub struct AppenderReport {
+    pub repository: String,
+    /// Commit made by this run, if any.
+    pub commit: Option<String>,
+    /// Whether the remote was unreachable, commits are then queued and pushed by a later run.
+    pub offline: bool,
+    /// Commits pushed by this run, with the ones queued by previous runs.
+    pub pushed: usize,
+    /// Local commits not pushed yet.
+    pub pending: usize,
+    pub duration_ms: u128,
+    pub links: Vec<LinkReport>,
+    pub errors: Vec<String>,
+    /// Push to every mirror, a failing mirror is not an error of the appender.
+    pub mirrors: Vec<MirrorReport>,
+    /// Why the appender was not processed, when locked by another run.
+    pub skipped: Option<String>,
+}
+
+#[derive(Debug, Serialize, Default)]
+pub struct LinkReport {
+    pub local_path: String,
+    pub source_path: String,
+    pub lines_added_locally: usize,
+    pub lines_added_remotely: usize,
+    /// Whether the file in the repository was rewritten.
+    pub remote_changed: bool,
+    pub error: Option<String>,
+    /// Why the link was not processed, when locked by another run.
+    pub skipped: Option<String>,
+    /// The content to commit, for a bare repository.
+    #[serde(skip)]
+    pub blob: Option<(Oid, FileMode)>,
+}
+
+#[derive(Debug, Serialize, Default)]
+pub struct MirrorReport {
+    pub name: String,
+    pub pushed: bool,
+    pub error: Option<String>,
+}
+
+impl RunReport {
+    pub fn new(appenders: Vec<AppenderReport>, duration_ms: u128) -> RunReport {
+        let failed = appenders
+            .iter()
+            .any(|a| !a.errors.is_empty() || a.links.iter().any(|l| l.error.is_some()));
+        let changed = appenders.iter().any(|a| {
+            a.commit.is_some() || a.pushed > 0 || a.links.iter().any(|l| l.lines_added_locally > 0)
+        });
+        let status = if failed {
+            RunStatus::PartialFailure
+        } else if changed {
+            RunStatus::Synced
+        } else {
+            RunStatus::NoChanges
+        };
+        RunReport {
+            status,
+            duration_ms,
+            appenders,
+        }
+    }
+
+    pub fn exit_code(&self) -> i32 {
+        match self.status {
+            RunStatus::NoChanges => EXIT_NO_CHANGES,
+            RunStatus::Synced => EXIT_SYNCED,
+            RunStatus::PartialFailure => EXIT_PARTIAL_FAILURE,
+        }
+    }
+}
+
+#[cfg(test)]
+pub mod tests {
+    use pretty_assertions::assert_eq;
+
+    use crate::report::{AppenderReport, LinkReport, RunReport, RunStatus};
+
+    #[test]
+    fn test_run_status() {
+        let appender = |commit: Option<String>, error: Option<String>| AppenderReport {
+            commit,
+            links: vec![LinkReport {
+                error,
+                ..LinkReport::default()
+            }],
+            ..AppenderReport::default()
+        };
+        assert_eq!(
+            RunStatus::NoChanges,
+            RunReport::new(vec![appender(None, None)], 0).status
+        );
+        assert_eq!(
+            RunStatus::Synced,
+            RunReport::new(
+                vec![appender(None, None), appender(Some("oid".to_owned()), None)],
+                0
+            )
+            .status
+        );
+        assert_eq!(
+            RunStatus::PartialFailure,
+            RunReport::new(
+                vec![
+                    appender(Some("oid".to_owned()), None),
+                    appender(None, Some("error".to_owned()))
+                ],
+                0
+            )
+            .status
+        );
+    }
+}
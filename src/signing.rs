@@ -0,0 +1,268 @@
+use std::io::Write;
+use std::process::{Command, Stdio};
+
+use git2::Repository;
+use tempfile::NamedTempFile;
+
+use crate::config::{Signing, SigningFormat};
+use crate::log;
+
+/// How commits are signed, resolved from the appender config and the repository config.
+#[derive(Debug, Clone, Eq, PartialEq)]
+pub struct Signer {
+    pub format: SigningFormat,
+    pub key: Option<String>,
+    pub program: String,
+}
+
+/// Commits are signed if the appender has `signing`, or if the repository has `commit.gpgsign`.
+/// Values missing from `signing` are read from `gpg.format` and `user.signingkey`.
+pub fn signer(repo: &Repository, signing: Option<&Signing>) -> Result<Option<Signer>, String> {
+    let repo_config = repo.config().ok();
+    let get_string = |key: &str| repo_config.as_ref()?.get_string(key).ok();
+    let enabled = signing.is_some()
+        || repo_config
+            .as_ref()
+            .and_then(|c| c.get_bool("commit.gpgsign").ok())
+            .unwrap_or(false);
+    if !enabled {
+        return Ok(None);
+    }
+    let format = match signing.and_then(|s| s.format.clone()) {
+        Some(format) => format,
+        None => match get_string("gpg.format").as_deref() {
+            Some("ssh") => SigningFormat::Ssh,
+            Some("openpgp") | None => SigningFormat::Openpgp,
+            Some(other) => return Err(format!("Unsupported gpg.format {}", other)),
+        },
+    };
+    let key = signing
+        .and_then(|s| s.key.clone())
+        .or_else(|| get_string("user.signingkey"));
+    let program = match format {
+        SigningFormat::Openpgp => get_string("gpg.openpgp.program")
+            .or_else(|| get_string("gpg.program"))
+            .unwrap_or("gpg".to_owned()),
+        SigningFormat::Ssh => get_string("gpg.ssh.program").unwrap_or("ssh-keygen".to_owned()),
+    };
+    Ok(Some(Signer {
+        format,
+        key,
+        program,
+    }))
+}
+
+impl Signer {
+    /// The detached, armored signature of a commit buffer.
+    pub fn sign(&self, content: &str) -> Result<String, String> {
+        log!("Signing with {:?} key {:?}", self.format, self.key);
+        match self.format {
+            SigningFormat::Openpgp => {
+                let mut args = vec!["--status-fd=2", "-bsa"];
+                if let Some(key) = &self.key {
+                    args.extend(["-u", key.as_str()]);
+                }
+                run(&self.program, &args, content)
+            }
+            SigningFormat::Ssh => {
+                let key = self
+                    .key
+                    .as_ref()
+                    .ok_or("An SSH signing key is required (user.signingkey)")?;
+                // a literal public key, its private key is in the SSH agent
+                if let Some(public_key) = key.strip_prefix("key::") {
+                    // one file per signature, appenders can be signing at the same time
+                    let mut key_file = NamedTempFile::with_prefix("git-append-")
+                        .map_err(|e| format!("Could not create the key file: {}", e))?;
+                    key_file
+                        .write_all(public_key.as_bytes())
+                        .map_err(|e| format!("Could not write the key file: {}", e))?;
+                    let key_path = key_file.path().to_string_lossy().into_owned();
+                    run(
+                        &self.program,
+                        &["-Y", "sign", "-n", "git", "-U", "-f", &key_path],
+                        content,
+                    )
+                } else {
+                    run(
+                        &self.program,
+                        &["-Y", "sign", "-n", "git", "-f", key],
+                        content,
+                    )
+                }
+            }
+        }
+    }
+}
+
+fn run(program: &str, args: &[&str], input: &str) -> Result<String, String> {
+    let mut child = Command::new(program)
+        .args(args)
+        .stdin(Stdio::piped())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped())
+        .spawn()
+        .map_err(|e| format!("Could not run {}: {}", program, e))?;
+    child
+        .stdin
+        .take()
+        .unwrap()
+        .write_all(input.as_bytes())
+        .map_err(|e| format!("Could not write to {}: {}", program, e))?;
+    let output = child
+        .wait_with_output()
+        .map_err(|e| format!("Could not run {}: {}", program, e))?;
+    if output.status.success() {
+        String::from_utf8(output.stdout).map_err(|e| e.to_string())
+    } else {
+        Err(format!(
+            "{} failed to sign: {}",
+            program,
+            String::from_utf8_lossy(&output.stderr).trim()
+        ))
+    }
+}
+
+#[cfg(test)]
+pub mod tests {
+    use pretty_assertions::assert_eq;
+    use std::fs;
+    use std::os::unix::fs::PermissionsExt;
+    use std::path::Path;
+    use std::process::Command;
+    use std::thread;
+
+    use git2::Repository;
+
+    use crate::config::{Signing, SigningFormat};
+    use crate::git::{commit, initial_commit, signature};
+    use crate::signing::{signer, Signer};
+
+    #[test]
+    fn test_signer() {
+        let folder =
+            std::env::temp_dir().join(format!("git-append-test-signer-{}", std::process::id()));
+        let _ = fs::remove_dir_all(&folder);
+        let repo = Repository::init(&folder).unwrap();
+
+        assert_eq!(Ok(None), signer(&repo, None));
+        let mut config = repo.config().unwrap();
+        config.set_bool("commit.gpgsign", true).unwrap();
+        config.set_str("gpg.format", "ssh").unwrap();
+        config.set_str("user.signingkey", "/keys/id").unwrap();
+        assert_eq!(
+            Ok(Some(Signer {
+                format: SigningFormat::Ssh,
+                key: Some("/keys/id".to_owned()),
+                program: "ssh-keygen".to_owned(),
+            })),
+            signer(&repo, None)
+        );
+        let signing = Signing {
+            format: Some(SigningFormat::Openpgp),
+            key: Some("ABCD".to_owned()),
+        };
+        assert_eq!(
+            Ok(Some(Signer {
+                format: SigningFormat::Openpgp,
+                key: Some("ABCD".to_owned()),
+                program: "gpg".to_owned(),
+            })),
+            signer(&repo, Some(&signing))
+        );
+        config.set_str("gpg.format", "x509").unwrap();
+        assert!(signer(&repo, None).is_err());
+
+        fs::remove_dir_all(&folder).unwrap();
+    }
+
+    /// Signs with a new SSH key, generated in `folder`.
+    pub fn ssh_signer(folder: &Path) -> Signer {
+        let key = folder.join("key").to_string_lossy().into_owned();
+        let generated = Command::new("ssh-keygen")
+            .args(["-q", "-t", "ed25519", "-N", "", "-f", &key])
+            .status()
+            .unwrap();
+        assert!(generated.success());
+        Signer {
+            format: SigningFormat::Ssh,
+            key: Some(key),
+            program: "ssh-keygen".to_owned(),
+        }
+    }
+
+    #[test]
+    fn test_sign_ssh() {
+        let folder =
+            std::env::temp_dir().join(format!("git-append-test-ssh-{}", std::process::id()));
+        let _ = fs::remove_dir_all(&folder);
+        let repo = Repository::init(&folder).unwrap();
+        initial_commit(&repo).unwrap();
+        let signer = ssh_signer(&folder);
+
+        fs::write(folder.join("synced"), "a\n").unwrap();
+        let oid = commit(
+            &repo,
+            &signature(&repo, None).unwrap(),
+            "chore(append)",
+            &[(&"synced".to_owned(), None)],
+            Some(&signer),
+        )
+        .unwrap()
+        .unwrap();
+        let (signed, _) = repo.extract_signature(&oid, None).unwrap();
+        assert!(signed
+            .as_str()
+            .unwrap()
+            .starts_with("-----BEGIN SSH SIGNATURE-----"));
+        assert_eq!(Some(oid), repo.head().unwrap().target());
+
+        fs::remove_dir_all(&folder).unwrap();
+    }
+
+    #[test]
+    fn test_sign_literal_key() {
+        let folder =
+            std::env::temp_dir().join(format!("git-append-test-key-{}", std::process::id()));
+        let _ = fs::remove_dir_all(&folder);
+        fs::create_dir_all(&folder).unwrap();
+        // prints the key file it is given, after giving the other threads time to write theirs
+        let program = folder.join("sign");
+        fs::write(&program, "#!/bin/sh\nsleep 0.2\ncat \"$7\"\n").unwrap();
+        fs::set_permissions(&program, fs::Permissions::from_mode(0o755)).unwrap();
+        let program = program.to_string_lossy().into_owned();
+
+        let signatures: Vec<(String, Result<String, String>)> = thread::scope(|scope| {
+            let signing: Vec<_> = (0..4)
+                .map(|i| {
+                    let key = format!("ssh-ed25519 AAAA{} someone", i);
+                    let signer = Signer {
+                        format: SigningFormat::Ssh,
+                        key: Some(format!("key::{}", key)),
+                        program: program.clone(),
+                    };
+                    scope.spawn(move || (key, signer.sign("content")))
+                })
+                .collect();
+            signing.into_iter().map(|s| s.join().unwrap()).collect()
+        });
+        for (key, signature) in signatures {
+            assert_eq!(Ok(key), signature);
+        }
+        let left: Vec<_> = fs::read_dir(std::env::temp_dir())
+            .unwrap()
+            .filter_map(|entry| entry.ok())
+            .filter(|entry| {
+                entry
+                    .file_name()
+                    .to_string_lossy()
+                    .starts_with("git-append-")
+                    && Path::new(&entry.path()).is_file()
+                    && fs::read_to_string(entry.path()).is_ok_and(|c| c.contains("AAAA"))
+            })
+            .collect();
+        assert!(left.is_empty());
+
+        fs::remove_dir_all(&folder).unwrap();
+    }
+}
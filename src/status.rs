@@ -0,0 +1,323 @@
+use git2::Repository;
+use serde::Serialize;
+use time::{format_description::well_known::Rfc3339, OffsetDateTime};
+
+use crate::{
+    appender::{get_from_appender, line_set},
+    config::{Config, GitLink},
+    core::folder_files,
+    file::get_file_contents_as_lines,
+    git::{is_dirty, last_change, open, pending_commits},
+};
+
+#[derive(Debug, Serialize)]
+pub struct AppenderStatus {
+    pub repository: String,
+    pub dirty: bool,
+    /// Commits not pushed yet, queued while the remote was unreachable.
+    pub pending_commits: usize,
+    pub links: Vec<LinkStatus>,
+    /// The repository could not be read, every link has this error too.
+    pub error: Option<String>,
+}
+
+#[derive(Debug, Serialize)]
+pub struct LinkStatus {
+    pub local_path: String,
+    pub source_path: String,
+    pub source_branch: String,
+    pub local_lines: usize,
+    pub remote_lines: usize,
+    pub only_local: usize,
+    pub only_remote: usize,
+    pub last_sync_commit: Option<String>,
+    pub last_sync_time: Option<String>,
+    pub encryption: String,
+    /// The link could not be compared, its counts are then 0.
+    pub error: Option<String>,
+}
+
+/// Compares every link with its last synced state (the last fetched remote branch, and the commits queued while
+/// offline), without any network access. A link failing is reported on its row.
+pub fn status(config: Config) -> Vec<AppenderStatus> {
+    let mut statuses: Vec<AppenderStatus> = config
+        .appenders
+        .iter()
+        .map(|(git_folder, appender)| {
+            let repo = open(git_folder);
+            let files = appender
+                .links
+                .iter()
+                .map(|(local_path, link)| Ok((link, local_path.clone(), link.source_path.clone())))
+                .chain(appender.folder_links.iter().flat_map(|(folder, link)| {
+                    match folder_files(folder, link) {
+                        Ok(files) => files
+                            .into_iter()
+                            .map(|(local_path, repo_file_path)| {
+                                Ok((link, local_path, repo_file_path))
+                            })
+                            .collect(),
+                        Err(e) => vec![Err((link, folder.clone(), e))],
+                    }
+                }));
+            let mut links: Vec<LinkStatus> = files
+                .map(|file| match (file, &repo) {
+                    (Ok((link, local_path, repo_file_path)), Ok(repo)) => {
+                        link_status(repo, link, &local_path, repo_file_path)
+                    }
+                    (Ok((link, local_path, repo_file_path)), Err(e)) => {
+                        failed_status(link, local_path, repo_file_path, e.clone())
+                    }
+                    (Err((link, folder, e)), _) => {
+                        failed_status(link, folder, link.source_path.clone(), e)
+                    }
+                })
+                .collect();
+            links.sort_by(|a, b| a.local_path.cmp(&b.local_path));
+            let pending_commits = repo
+                .as_ref()
+                .map_err(String::clone)
+                .and_then(|repo| pending_commits(repo, "master"));
+            AppenderStatus {
+                repository: git_folder.clone(),
+                dirty: repo.as_ref().is_ok_and(is_dirty),
+                pending_commits: *pending_commits.as_ref().unwrap_or(&0),
+                links,
+                error: pending_commits.err(),
+            }
+        })
+        .collect();
+    statuses.sort_by(|a, b| a.repository.cmp(&b.repository));
+    statuses
+}
+
+fn link_status(
+    repo: &Repository,
+    link: &GitLink,
+    local_path: &String,
+    repo_file_path: String,
+) -> LinkStatus {
+    let features = link.features.clone().unwrap_or_default();
+    let (local, _) = get_file_contents_as_lines(local_path).unwrap_or_default();
+    let (remote, _) = match get_from_appender(link, repo, &repo_file_path) {
+        Ok(remote) => remote,
+        Err(e) => return failed_status(link, local_path.clone(), repo_file_path, e),
+    };
+    let local = line_set(local, &features);
+    let remote = line_set(remote, &features);
+    let source_branch = link.source_branch.clone().unwrap_or("master".to_owned());
+    let last_sync = last_change(
+        repo,
+        &repo_file_path,
+        format!("http-origin/{}", source_branch),
+    );
+
+    LinkStatus {
+        local_lines: local.len(),
+        remote_lines: remote.len(),
+        only_local: local.difference(&remote).count(),
+        only_remote: remote.difference(&local).count(),
+        last_sync_commit: last_sync.as_ref().map(|c| c.id().to_string()),
+        last_sync_time: last_sync.and_then(|c| {
+            OffsetDateTime::from_unix_timestamp(c.time().seconds())
+                .ok()?
+                .format(&Rfc3339)
+                .ok()
+        }),
+        ..empty_status(link, local_path.clone(), repo_file_path)
+    }
+}
+
+fn failed_status(
+    link: &GitLink,
+    local_path: String,
+    repo_file_path: String,
+    error: String,
+) -> LinkStatus {
+    LinkStatus {
+        error: Some(error),
+        ..empty_status(link, local_path, repo_file_path)
+    }
+}
+
+/// A link without any line nor sync.
+fn empty_status(link: &GitLink, local_path: String, repo_file_path: String) -> LinkStatus {
+    LinkStatus {
+        local_path,
+        source_path: repo_file_path,
+        source_branch: link.source_branch.clone().unwrap_or("master".to_owned()),
+        local_lines: 0,
+        remote_lines: 0,
+        only_local: 0,
+        only_remote: 0,
+        last_sync_commit: None,
+        last_sync_time: None,
+        encryption: if link.password_file.is_some() {
+            "age passphrase".to_owned()
+        } else {
+            "none".to_owned()
+        },
+        error: None,
+    }
+}
+
+pub fn print_status(statuses: &[AppenderStatus]) {
+    for appender in statuses {
+        println!(
+            "{}{}",
+            appender.repository,
+            if appender.dirty { " (dirty)" } else { "" }
+        );
+        if let Some(error) = &appender.error {
+            println!("  error: {}", error);
+        }
+        if appender.pending_commits > 0 {
+            println!("  {} commit(s) pending push", appender.pending_commits);
+        }
+        for link in appender.links.iter() {
+            println!(
+                "  {} -> {} ({}, encryption: {})",
+                link.local_path, link.source_path, link.source_branch, link.encryption
+            );
+            if let Some(error) = &link.error {
+                println!("    error: {}", error);
+                continue;
+            }
+            println!(
+                "    local: {} lines ({} only local), remote: {} lines ({} only remote)",
+                link.local_lines, link.only_local, link.remote_lines, link.only_remote
+            );
+            match (&link.last_sync_commit, &link.last_sync_time) {
+                (Some(commit), Some(time)) => println!("    last sync: {} at {}", commit, time),
+                _ => println!("    never synced"),
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+pub mod tests {
+    use pretty_assertions::assert_eq;
+    use std::collections::HashMap;
+    use std::fs;
+    use std::path::Path;
+
+    use git2::{Repository, Signature};
+
+    use crate::config::{Config, GitAppender, GitLink};
+    use crate::status::{status, AppenderStatus};
+
+    #[test]
+    fn test_status() {
+        let folder =
+            std::env::temp_dir().join(format!("git-append-test-status-{}", std::process::id()));
+        let _ = fs::remove_dir_all(&folder);
+        let repo_folder = folder.join("repo");
+        let repo = Repository::init(&repo_folder).unwrap();
+        fs::write(repo_folder.join("synced"), "a\nb\n").unwrap();
+        fs::write(repo_folder.join("diverged"), "a\nb\nremote\n").unwrap();
+        let mut index = repo.index().unwrap();
+        index.add_path(Path::new("synced")).unwrap();
+        index.add_path(Path::new("diverged")).unwrap();
+        index.write().unwrap();
+        let tree = repo.find_tree(index.write_tree().unwrap()).unwrap();
+        let sign = Signature::now("Someone", "someone@example.com").unwrap();
+        let oid = repo
+            .commit(Some("HEAD"), &sign, &sign, "sync", &tree, &[])
+            .unwrap();
+        repo.reference("refs/remotes/http-origin/master", oid, true, "fetch")
+            .unwrap();
+
+        let link = |source_path: &str| -> GitLink {
+            serde_json::from_value(serde_json::json!({ "source_path": source_path })).unwrap()
+        };
+        let local = |name: &str| folder.join(name).to_string_lossy().into_owned();
+        fs::write(local("synced"), "b\na\n").unwrap();
+        fs::write(local("diverged"), "a\nlocal 1\nlocal 2\n").unwrap();
+        let repository = repo_folder.to_string_lossy().into_owned();
+        let config = Config {
+            appenders: HashMap::from([(
+                repository.clone(),
+                GitAppender {
+                    links: HashMap::from([
+                        (local("synced"), link("synced")),
+                        (local("diverged"), link("diverged")),
+                    ]),
+                    ..GitAppender::default()
+                },
+            )]),
+            ..Config::default()
+        };
+
+        let statuses = status(config.clone());
+        assert_eq!(1, statuses.len());
+        assert!(!statuses[0].dirty);
+        let counts: Vec<(String, usize, usize, usize, usize)> = statuses[0]
+            .links
+            .iter()
+            .map(|l| {
+                (
+                    l.source_path.clone(),
+                    l.local_lines,
+                    l.remote_lines,
+                    l.only_local,
+                    l.only_remote,
+                )
+            })
+            .collect();
+        assert_eq!(
+            vec![
+                ("diverged".to_owned(), 3, 3, 2, 2),
+                ("synced".to_owned(), 2, 2, 0, 0)
+            ],
+            counts
+        );
+        assert_eq!(Some(oid.to_string()), statuses[0].links[0].last_sync_commit);
+
+        fs::write(repo_folder.join("synced"), "a\nb\nc\n").unwrap();
+        assert!(status(config.clone())[0].dirty);
+
+        // one failing link or repository does not hide the others
+        let mut config = config;
+        let encrypted: GitLink = serde_json::from_value(serde_json::json!({
+            "source_path": "synced",
+            "password_file": local("missing-password")
+        }))
+        .unwrap();
+        config
+            .appenders
+            .get_mut(&repository)
+            .unwrap()
+            .links
+            .insert(local("encrypted"), encrypted);
+        config.appenders.insert(
+            local("missing"),
+            GitAppender {
+                links: HashMap::from([(local("synced"), link("synced"))]),
+                ..GitAppender::default()
+            },
+        );
+        let statuses = status(config);
+        let rows = |status: &AppenderStatus| -> Vec<(String, bool)> {
+            status
+                .links
+                .iter()
+                .map(|l| (l.local_path.clone(), l.error.is_some()))
+                .collect()
+        };
+        assert_eq!(local("missing"), statuses[0].repository);
+        assert!(statuses[0].error.is_some());
+        assert_eq!(vec![(local("synced"), true)], rows(&statuses[0]));
+        assert_eq!(None, statuses[1].error);
+        assert_eq!(
+            vec![
+                (local("diverged"), false),
+                (local("encrypted"), true),
+                (local("synced"), false)
+            ],
+            rows(&statuses[1])
+        );
+
+        fs::remove_dir_all(&folder).unwrap();
+    }
+}
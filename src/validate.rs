@@ -0,0 +1,353 @@
+use std::collections::HashMap;
+use std::fmt;
+use std::fs;
+use std::os::unix::fs::PermissionsExt;
+use std::path::{Path, PathBuf};
+
+use git2::Repository;
+use regex::bytes::Regex;
+
+use crate::config::{self, GitLink};
+use crate::file::{expand_path, line_column, load_config, parse_mode};
+use crate::git::parse_sha256;
+
+pub struct Diagnostic {
+    /// `None` when the message already holds its location (e.g. parse errors).
+    pub file: Option<PathBuf>,
+    pub position: Option<(usize, usize)>,
+    pub message: String,
+}
+
+impl fmt::Display for Diagnostic {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match (&self.file, self.position) {
+            (Some(file), Some((line, column))) => {
+                write!(
+                    f,
+                    "{}:{}:{}: {}",
+                    file.display(),
+                    line,
+                    column,
+                    self.message
+                )
+            }
+            (Some(file), None) => write!(f, "{}: {}", file.display(), self.message),
+            (None, _) => write!(f, "{}", self.message),
+        }
+    }
+}
+
+/// Checks everything that would otherwise only fail during a run.
+pub fn validate(path: String) -> Vec<Diagnostic> {
+    let mut loaded = Vec::new();
+    let config = load_config(Path::new(&path), &mut Vec::new(), &mut loaded)
+        .map(|config| config.apply_host(&config::hostname()))
+        .and_then(|config| config.resolve_defaults());
+    let config = match config {
+        Ok(config) => config,
+        Err(message) => {
+            return vec![Diagnostic {
+                file: None,
+                position: None,
+                message,
+            }]
+        }
+    };
+    let sources = Sources::read(loaded);
+
+    let mut diagnostics = Vec::new();
+    for (git_folder, appender) in config.appenders.iter() {
+        let repo_error = match Repository::open(git_folder) {
+            Ok(repo) => repo
+                .find_remote("http-origin")
+                .err()
+                .map(|_| format!("{} has no `http-origin` remote", git_folder)),
+            // created on the first run
+            Err(_) if appender.bare == Some(true) && appender.url.is_some() => None,
+            Err(e) => Some(format!("{} is not a git repository: {}", git_folder, e)),
+        };
+        if let Some(message) = repo_error {
+            diagnostics.push(sources.diagnostic(&[git_folder], message));
+        }
+
+        if let Some(git_config) = &appender.git_config {
+            if let Some(message) = check_secret_file(&git_config.token_file) {
+                diagnostics
+                    .push(sources.diagnostic(&[git_folder, &git_config.token_file], message));
+            }
+        }
+
+        let fingerprints = appender.certificate_sha256.iter().chain(
+            appender
+                .mirrors
+                .values()
+                .filter_map(|m| m.certificate_sha256.as_ref()),
+        );
+        for fingerprint in fingerprints {
+            if let Err(message) = parse_sha256(fingerprint) {
+                diagnostics.push(sources.diagnostic(&[git_folder, fingerprint], message));
+            }
+        }
+
+        let mut targets: HashMap<(String, String), Vec<&String>> = HashMap::new();
+        for (local_path, link) in appender.links.iter().chain(appender.folder_links.iter()) {
+            diagnostics.extend(check_link(&sources, git_folder, local_path, link));
+            targets
+                .entry((
+                    link.source_branch.clone().unwrap_or("master".to_owned()),
+                    link.source_path.clone(),
+                ))
+                .or_default()
+                .push(local_path);
+        }
+        for ((_, source_path), local_paths) in targets.iter() {
+            if local_paths.len() > 1 {
+                let mut local_paths = local_paths.clone();
+                local_paths.sort();
+                diagnostics.push(sources.diagnostic(
+                    &[git_folder, source_path],
+                    format!(
+                        "{} in {} is the target of several links: {:?}",
+                        source_path, git_folder, local_paths
+                    ),
+                ));
+            }
+        }
+    }
+    if let Some(ca_file) = config.network.as_ref().and_then(|n| n.ca_file.as_ref()) {
+        if !Path::new(ca_file).is_file() {
+            diagnostics
+                .push(sources.diagnostic(&[ca_file], format!("CA file {} not found", ca_file)));
+        }
+    }
+    diagnostics.sort_by(|a, b| (&a.file, a.position).cmp(&(&b.file, b.position)));
+    diagnostics
+}
+
+fn check_link(
+    sources: &Sources,
+    git_folder: &str,
+    local_path: &str,
+    link: &GitLink,
+) -> Vec<Diagnostic> {
+    let mut diagnostics = Vec::new();
+    for pattern in link.exclude_patterns.iter().flatten() {
+        if let Err(e) = Regex::new(pattern) {
+            diagnostics.push(sources.diagnostic(
+                &[git_folder, local_path, pattern],
+                format!("Invalid exclude pattern for {}: {}", local_path, e),
+            ));
+        }
+    }
+    if let Some(password_file) = &link.password_file {
+        if let Some(message) = check_secret_file(password_file) {
+            diagnostics.push(sources.diagnostic(&[git_folder, local_path, password_file], message));
+        }
+    }
+    if let Some(mode) = &link.mode {
+        if let Err(e) = parse_mode(mode) {
+            diagnostics.push(sources.diagnostic(
+                &[git_folder, local_path, mode],
+                format!("{} for {}", e, local_path),
+            ));
+        }
+    }
+    diagnostics
+}
+
+/// The file must exist and must not be readable by group or others.
+fn check_secret_file(path: &String) -> Option<String> {
+    match fs::metadata(path) {
+        Ok(metadata) => {
+            let mode = metadata.permissions().mode() & 0o777;
+            if mode & 0o077 != 0 {
+                Some(format!(
+                    "{} has unsafe permissions {:o}, it should not be accessible by group or others (chmod 600)",
+                    path, mode
+                ))
+            } else {
+                None
+            }
+        }
+        Err(e) => Some(format!("Cannot read {}: {}", path, e)),
+    }
+}
+
+/// Raw content of the config files, to locate a value in them.
+struct Sources(Vec<Source>);
+
+struct Source {
+    path: PathBuf,
+    content: String,
+    /// Every key and string value as written in the file (before `~` and variables are expanded).
+    strings: Vec<String>,
+}
+
+impl Sources {
+    fn read(paths: Vec<PathBuf>) -> Sources {
+        Sources(
+            paths
+                .into_iter()
+                .map(|path| {
+                    let content = fs::read_to_string(&path).unwrap_or_default();
+                    let mut strings = Vec::new();
+                    if let Some(value) = parse_value(&path, &content) {
+                        collect_strings(value, &mut strings);
+                    }
+                    Source {
+                        path,
+                        content,
+                        strings,
+                    }
+                })
+                .collect(),
+        )
+    }
+
+    /// Points to the last value of `path`, each value being searched after the previous one
+    /// (e.g. an appender, one of its links, then a value of the link).
+    /// Leading values are dropped until it is found, otherwise it points to the main config file.
+    fn diagnostic(&self, path: &[&str], message: String) -> Diagnostic {
+        (0..path.len())
+            .find_map(|start| {
+                self.0.iter().find_map(|source| {
+                    source.locate(&path[start..]).map(|offset| Diagnostic {
+                        file: Some(source.path.clone()),
+                        position: Some(line_column(&source.content, offset)),
+                        message: message.clone(),
+                    })
+                })
+            })
+            .unwrap_or_else(|| Diagnostic {
+                file: self.0.first().map(|s| s.path.clone()),
+                position: None,
+                message,
+            })
+    }
+}
+
+impl Source {
+    fn locate(&self, path: &[&str]) -> Option<usize> {
+        let mut from = 0;
+        let mut found = None;
+        for value in path {
+            let offset = self
+                .spellings(value)
+                .iter()
+                .filter_map(|raw| self.content[from..].find(raw.as_str()))
+                .min()?
+                + from;
+            found = Some(offset);
+            from = offset + 1;
+        }
+        found
+    }
+
+    /// How `value` can be written in the file: as one of its raw strings that expands to it, possibly escaped.
+    fn spellings(&self, value: &str) -> Vec<String> {
+        let mut spellings: Vec<String> = self
+            .strings
+            .iter()
+            .filter(|raw| *raw == value || expand_path(raw).is_ok_and(|e| e == value))
+            .cloned()
+            .collect();
+        if spellings.is_empty() {
+            spellings.push(value.to_owned());
+        }
+        let escaped: Vec<String> = spellings
+            .iter()
+            .map(|s| serde_json::to_string(s).unwrap())
+            .map(|s| s[1..s.len() - 1].to_owned())
+            .collect();
+        spellings.extend(escaped);
+        spellings.retain(|s| !s.is_empty());
+        spellings
+    }
+}
+
+fn parse_value(path: &Path, content: &str) -> Option<serde_json::Value> {
+    match path.extension().and_then(|e| e.to_str()) {
+        Some("toml") => toml::from_str(content).ok(),
+        Some("yaml") | Some("yml") => serde_yaml::from_str(content).ok(),
+        _ => serde_json::from_str(content).ok(),
+    }
+}
+
+fn collect_strings(value: serde_json::Value, strings: &mut Vec<String>) {
+    match value {
+        serde_json::Value::String(s) => strings.push(s),
+        serde_json::Value::Array(values) => {
+            values.into_iter().for_each(|v| collect_strings(v, strings))
+        }
+        serde_json::Value::Object(map) => {
+            for (key, value) in map {
+                strings.push(key);
+                collect_strings(value, strings);
+            }
+        }
+        _ => {}
+    }
+}
+
+#[cfg(test)]
+pub mod tests {
+    use pretty_assertions::assert_eq;
+
+    use crate::validate::validate;
+
+    #[test]
+    fn test_validate_example_config() {
+        let diagnostics: Vec<String> = validate(String::from("tests/invalid-config.json"))
+            .into_iter()
+            .map(|d| d.to_string())
+            .collect();
+        assert_eq!(5, diagnostics.len());
+        assert!(diagnostics[0].starts_with(
+            "tests/invalid-config.json:3:6: /nonexistent/repository-location is not a git repository"
+        ));
+        assert_eq!(
+            vec![
+                "tests/invalid-config.json:6:27: file_in_git in /nonexistent/repository-location is the target of several links: [\"/home/local/other_file\", \"/home/local/plaintext_file\"]",
+                "tests/invalid-config.json:8:14: Invalid exclude pattern for /home/local/plaintext_file: regex parse error:\n    (unclosed\n    ^\nerror: unclosed group",
+                "tests/invalid-config.json:13:29: Cannot read /nonexistent/password-file: No such file or directory (os error 2)",
+                "tests/invalid-config.json:14:20: Invalid mode 0800, expected an octal mode such as 0600 for /home/local/other_file",
+            ],
+            diagnostics[1..]
+        );
+    }
+
+    #[test]
+    fn test_validate_expanded_values() {
+        let home = std::env::var("HOME").unwrap();
+        let diagnostics: Vec<String> = validate(String::from("tests/invalid-expanded-config.json"))
+            .into_iter()
+            .map(|d| d.to_string())
+            .collect();
+        let located: Vec<&str> = diagnostics
+            .iter()
+            .map(|d| d.split(": ").next().unwrap())
+            .collect();
+        assert_eq!(
+            vec![
+                "tests/invalid-expanded-config.json:3:6",
+                "tests/invalid-expanded-config.json:5:62",
+                "tests/invalid-expanded-config.json:8:6",
+                "tests/invalid-expanded-config.json:10:62",
+            ],
+            located
+        );
+        assert!(diagnostics[3].contains(&format!("{}/nonexistent-password", home)));
+    }
+
+    #[test]
+    fn test_validate_parse_error() {
+        let diagnostics: Vec<String> = validate(String::from("tests/multilines"))
+            .into_iter()
+            .map(|d| d.to_string())
+            .collect();
+        assert_eq!(
+            vec!["tests/multilines:1:1: expected value at line 1 column 1"],
+            diagnostics
+        );
+    }
+}